@@ -0,0 +1,321 @@
+use std::cmp::Reverse;
+use std::fs::{self, File};
+use std::os::unix::fs::MetadataExt as _;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+use std::{fmt, io};
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+
+use crate::{btrfs, subvolume};
+
+/// Extension of the marker files that pin backups
+///
+/// Pins are sidecar files rather than xattrs so read-only backups can be pinned too. An empty
+/// marker pins the backup indefinitely, otherwise it contains the time the pin expires.
+const PIN_EXTENSION: &str = "pin";
+
+#[derive(Debug)]
+pub struct Backup {
+    pub path: PathBuf,
+    /// Either a subvolume or a plain directory
+    pub kind: subvolume::Kind,
+    pub created: DateTime<Utc>,
+    pub source: Source,
+    pub pin: Option<Pin>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Pin {
+    Forever,
+    Until(DateTime<Utc>),
+}
+
+impl Backup {
+    pub fn is_pinned(&self, now: DateTime<Utc>) -> bool {
+        match self.pin {
+            None => false,
+            Some(Pin::Forever) => true,
+            Some(Pin::Until(expires)) => now < expires,
+        }
+    }
+
+    pub fn pin_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".");
+        path.push(PIN_EXTENSION);
+        path.into()
+    }
+}
+
+impl fmt::Display for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Forever => f.write_str("forever"),
+            Self::Until(expires) => write!(f, "until {}", expires.format("%Y-%m-%d %H:%M:%S UTC")),
+        }
+    }
+}
+
+/// How the old root volume is turned into a backup
+#[derive(Debug, Clone, Copy)]
+pub enum Mode {
+    /// Move the root volume into the backups directory
+    Rename,
+    /// Take a read-only snapshot of the root volume, then delete it
+    Snapshot,
+}
+
+impl FromStr for Mode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rename" => Ok(Self::Rename),
+            "snapshot" => Ok(Self::Snapshot),
+            _ => Err("expected rename or snapshot"),
+        }
+    }
+}
+
+/// What snapshot mode does with subvolumes nested inside the root volume, which snapshots don't
+/// include
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Nested {
+    /// Move the root volume into the backups instead, so they are kept
+    Rename,
+    /// Snapshot the root volume anyway, and delete them along with it
+    Delete,
+}
+
+impl FromStr for Nested {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rename" => Ok(Self::Rename),
+            "delete" => Ok(Self::Delete),
+            _ => Err("expected rename or delete"),
+        }
+    }
+}
+
+/// Where a backup's creation time came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Name,
+    Otime,
+    Btime,
+    Mtime,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Name => "name",
+            Self::Otime => "subvolume otime",
+            Self::Btime => "birth time",
+            Self::Mtime => "mtime",
+        })
+    }
+}
+
+/// List the backups in `dir`, newest first.
+///
+/// Creation times are parsed from the backup names using `format`, falling back to the subvolume
+/// otime, the birth time, and then the mtime. Entries without any usable time are reported and left
+/// out, so they are never removed. So is anything that isn't a subvolume on filesystem `fsid`,
+/// except plain directories if `plain_dirs` says to remove them.
+pub fn list(
+    dir: &Path,
+    format: &str,
+    fsid: &[u8; 16],
+    plain_dirs: subvolume::PlainDirs,
+) -> io::Result<Vec<Backup>> {
+    let mut backups = Vec::new();
+    for entry in dir.read_dir()? {
+        let entry = match entry {
+            Ok(ok) => ok,
+            Err(err) => {
+                log::warn!("skipping backup: {err}");
+                continue;
+            }
+        };
+
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == PIN_EXTENSION) {
+            if fs::symlink_metadata(path.with_extension("")).is_err() {
+                log::warn!("pin marker without a backup: '{}'", path.display());
+            }
+            continue;
+        }
+
+        let kind = match subvolume::kind(&path, fsid) {
+            Ok(kind) => kind,
+            Err(err) => {
+                log::warn!("skipping backup '{}': {err}", path.display());
+                continue;
+            }
+        };
+        match kind {
+            subvolume::Kind::Subvolume => {}
+            subvolume::Kind::PlainDir if plain_dirs == subvolume::PlainDirs::Remove => {}
+            subvolume::Kind::PlainDir => {
+                log::info!(
+                    "skipping backup that is a plain directory: '{}'",
+                    path.display()
+                );
+                continue;
+            }
+            _ => {
+                log::warn!("skipping backup that is {kind}: '{}'", path.display());
+                continue;
+            }
+        }
+
+        let parsed = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| parse_name(name, format));
+        let (created, source) = match parsed {
+            Some(created) => (created, Source::Name),
+            None => match created(&path) {
+                Ok(fallback) => fallback,
+                Err(err) => {
+                    log::warn!(
+                        "skipping backup without a usable creation time '{}': {err}",
+                        path.display()
+                    );
+                    continue;
+                }
+            },
+        };
+
+        if source != Source::Name {
+            log::warn!(
+                "backup name does not match the backup format, using its {source}: '{}'",
+                path.display()
+            );
+        }
+
+        let mut backup = Backup {
+            path,
+            kind,
+            created,
+            source,
+            pin: None,
+        };
+        backup.pin = read_pin(&backup.pin_path());
+        backups.push(backup);
+    }
+
+    backups.sort_unstable_by_key(|backup| Reverse(backup.created));
+    Ok(backups)
+}
+
+/// Parse a backup name created with `format`
+///
+/// Names are formatted in UTC, so formats without an offset are parsed as UTC. Formats without a
+/// time of day are taken as midnight.
+fn parse_name(name: &str, format: &str) -> Option<DateTime<Utc>> {
+    if let Ok(created) = DateTime::parse_from_str(name, format) {
+        return Some(created.to_utc());
+    }
+    if let Ok(created) = NaiveDateTime::parse_from_str(name, format) {
+        return Some(created.and_utc());
+    }
+    NaiveDate::parse_from_str(name, format)
+        .ok()
+        .map(|date| date.and_time(NaiveTime::MIN).and_utc())
+}
+
+/// When the old root volume at `path` was created, or `None` if there is no root volume
+///
+/// Uses the same sources as backups without a parseable name, falling back to the current time if
+/// none of them are available so a missing timestamp never stops the wipe.
+pub fn root_created(path: &Path) -> io::Result<Option<DateTime<Utc>>> {
+    match created(path) {
+        Ok((created, source)) => {
+            log::debug!("root volume created {created} according to its {source}");
+            Ok(Some(created))
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) if fs::symlink_metadata(path).is_ok() => {
+            log::warn!("failed to get root volume creation time, using current time: {err}");
+            Ok(Some(SystemTime::now().into()))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Best guess at when `path` was created: its subvolume otime, birth time, or mtime
+fn created(path: &Path) -> io::Result<(DateTime<Utc>, Source)> {
+    let metadata = fs::symlink_metadata(path)?;
+
+    // Only the root directory of a subvolume has the subvolume's otime
+    if metadata.is_dir() && metadata.ino() == btrfs::FIRST_FREE_OBJECTID {
+        match File::open(path).and_then(btrfs::subvolume_info) {
+            Ok(btrfs::SubvolumeInfo {
+                otime: Some(otime), ..
+            }) => return Ok((otime.into(), Source::Otime)),
+            Ok(_) => log::debug!("subvolume has no otime: '{}'", path.display()),
+            Err(err) => log::debug!("failed to get otime of '{}': {err}", path.display()),
+        }
+    }
+
+    match metadata.created() {
+        Ok(created) => return Ok((created.into(), Source::Btime)),
+        Err(err) => log::debug!("failed to get birth time of '{}': {err}", path.display()),
+    }
+
+    Ok((metadata.modified()?.into(), Source::Mtime))
+}
+
+fn read_pin(marker: &Path) -> Option<Pin> {
+    let contents = match fs::read_to_string(marker) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return None,
+        Err(err) => {
+            log::warn!(
+                "failed to read pin marker, pinning indefinitely '{}': {err}",
+                marker.display()
+            );
+            return Some(Pin::Forever);
+        }
+    };
+
+    let contents = contents.trim();
+    if contents.is_empty() {
+        return Some(Pin::Forever);
+    }
+
+    match parse_expiry(contents) {
+        Some(expires) => Some(Pin::Until(expires)),
+        None => {
+            log::warn!(
+                "invalid pin expiry, pinning indefinitely '{}': {contents}",
+                marker.display()
+            );
+            Some(Pin::Forever)
+        }
+    }
+}
+
+/// Parse a pin expiry as an RFC 3339 timestamp, a UTC date and time, or a UTC date
+///
+/// A bare date pins through the end of that day.
+fn parse_expiry(s: &str) -> Option<DateTime<Utc>> {
+    if let Ok(expires) = DateTime::parse_from_rfc3339(s) {
+        return Some(expires.to_utc());
+    }
+    for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"] {
+        if let Ok(expires) = NaiveDateTime::parse_from_str(s, format) {
+            return Some(expires.and_utc());
+        }
+    }
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.succ_opt())
+        .map(|date| date.and_time(NaiveTime::MIN).and_utc())
+}
@@ -0,0 +1,376 @@
+//! Thin wrappers around the btrfs ioctls.
+
+use std::ffi::{OsStr, c_void};
+use std::fs::{File, OpenOptions};
+use std::os::fd::{AsFd, AsRawFd as _};
+use std::os::unix::ffi::OsStrExt as _;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use std::{io, mem, ptr};
+
+use linux_raw_sys::btrfs;
+use rustix::io::Errno;
+use rustix::ioctl::{Getter, Ioctl, IoctlOutput, NoArg, Opcode, Setter, Updater, ioctl, opcode};
+
+/// Inode number of the root directory of every subvolume
+pub const FIRST_FREE_OBJECTID: u64 = btrfs::BTRFS_FIRST_FREE_OBJECTID as u64;
+
+/// Filesystem type reported by statfs
+pub const SUPER_MAGIC: u32 = linux_raw_sys::general::BTRFS_SUPER_MAGIC;
+
+const MAGIC: u8 = btrfs::BTRFS_IOCTL_MAGIC as u8;
+
+/// Device used for ioctls that aren't about a mounted filesystem
+const CONTROL: &str = "/dev/btrfs-control";
+
+const SCAN_DEV: Opcode = opcode::write::<btrfs::btrfs_ioctl_vol_args>(MAGIC, 4);
+const SYNC: Opcode = opcode::none(MAGIC, 8);
+const TREE_SEARCH: Opcode = opcode::read_write::<btrfs::btrfs_ioctl_search_args>(MAGIC, 17);
+const INO_LOOKUP: Opcode = opcode::read_write::<btrfs::btrfs_ioctl_ino_lookup_args>(MAGIC, 18);
+const SNAP_CREATE_V2: Opcode = opcode::write::<btrfs::btrfs_ioctl_vol_args_v2>(MAGIC, 23);
+const SUBVOL_CREATE_V2: Opcode = opcode::write::<btrfs::btrfs_ioctl_vol_args_v2>(MAGIC, 24);
+const FS_INFO: Opcode = opcode::read::<btrfs::btrfs_ioctl_fs_info_args>(MAGIC, 31);
+const DEVICES_READY: Opcode = opcode::read::<btrfs::btrfs_ioctl_vol_args>(MAGIC, 39);
+const GET_SUBVOL_INFO: Opcode = opcode::read::<btrfs::btrfs_ioctl_get_subvol_info_args>(MAGIC, 60);
+const SNAP_DESTROY_V2: Opcode = opcode::write::<btrfs::btrfs_ioctl_vol_args_v2>(MAGIC, 63);
+const SUBVOL_SYNC_WAIT: Opcode = opcode::write::<btrfs::btrfs_ioctl_subvol_wait>(MAGIC, 65);
+
+/// Wait for all currently queued subvolume deletions
+const SUBVOL_SYNC_WAIT_FOR_QUEUED: u32 = 1;
+
+/// Get the UUID of the filesystem containing `fd`
+pub fn fsid(fd: impl AsFd) -> io::Result<[u8; 16]> {
+    // SAFETY: btrfs_ioctl_fs_info_args is plain old data, so all zeroes is valid. Its flags are an
+    // input, so it can't be left uninitialized.
+    let mut args: btrfs::btrfs_ioctl_fs_info_args = unsafe { mem::zeroed() };
+    // SAFETY: FS_INFO takes a btrfs_ioctl_fs_info_args
+    unsafe { ioctl(fd, Updater::<FS_INFO, _>::new(&mut args))? };
+    Ok(args.fsid)
+}
+
+#[derive(Debug, Clone)]
+pub struct SubvolumeInfo {
+    pub id: u64,
+    pub read_only: bool,
+    /// When the subvolume was created, if known
+    pub otime: Option<SystemTime>,
+}
+
+/// Get information about the subvolume containing `fd`.
+pub fn subvolume_info(fd: impl AsFd) -> io::Result<SubvolumeInfo> {
+    // SAFETY: GET_SUBVOL_INFO fills in a btrfs_ioctl_get_subvol_info_args
+    let info = unsafe {
+        ioctl(
+            fd,
+            Getter::<GET_SUBVOL_INFO, btrfs::btrfs_ioctl_get_subvol_info_args>::new(),
+        )?
+    };
+
+    Ok(SubvolumeInfo {
+        id: info.treeid,
+        read_only: info.flags & u64::from(btrfs::BTRFS_ROOT_SUBVOL_RDONLY) != 0,
+        otime: timespec(info.otime),
+    })
+}
+
+/// A subvolume nested directly inside another one
+#[derive(Debug, Clone)]
+pub struct NestedSubvolume {
+    pub id: u64,
+    /// Path relative to the root of the containing subvolume
+    pub path: PathBuf,
+}
+
+/// List the subvolumes nested directly inside subvolume `id`
+///
+/// `fd` can be any file on the filesystem. This needs `CAP_SYS_ADMIN`.
+pub fn nested_subvolumes(fd: impl AsFd, id: u64) -> io::Result<Vec<NestedSubvolume>> {
+    // struct btrfs_root_ref { __le64 dirid; __le64 sequence; __le16 name_len; } followed by the name
+    const ROOT_REF_LEN: usize = 18;
+
+    let fd = fd.as_fd();
+    let mut nested = Vec::new();
+    search(fd, id, btrfs::BTRFS_ROOT_REF_KEY, |offset, item| {
+        if item.len() < ROOT_REF_LEN {
+            return Ok(());
+        }
+        let dirid = u64::from_le_bytes(item[0..8].try_into().unwrap());
+        let name_len = u16::from_le_bytes(item[16..18].try_into().unwrap());
+        let Some(name) = item[ROOT_REF_LEN..].get(..name_len.into()) else {
+            return Err(Errno::OVERFLOW.into());
+        };
+
+        let dir = ino_lookup(fd, id, dirid)?;
+        nested.push(NestedSubvolume {
+            id: offset,
+            path: dir.join(OsStr::from_bytes(name)),
+        });
+        Ok(())
+    })?;
+    Ok(nested)
+}
+
+/// Get the ID of the default subvolume, which is mounted when no subvolume is given
+///
+/// `fd` can be any file on the filesystem. This needs `CAP_SYS_ADMIN`.
+pub fn default_subvolume(fd: impl AsFd) -> io::Result<u64> {
+    // struct btrfs_dir_item { struct btrfs_disk_key location; __le64 transid; __le16 data_len;
+    // __le16 name_len; __u8 type; } followed by the name. The location starts with the objectid.
+    const DIR_ITEM_LEN: usize = 30;
+
+    let mut default = None;
+    search(
+        fd,
+        btrfs::BTRFS_ROOT_TREE_DIR_OBJECTID.into(),
+        btrfs::BTRFS_DIR_ITEM_KEY,
+        |_, item| {
+            if item.len() < DIR_ITEM_LEN {
+                return Ok(());
+            }
+            let name_len = u16::from_le_bytes(item[27..29].try_into().unwrap());
+            if item[DIR_ITEM_LEN..].get(..name_len.into()) == Some(b"default") {
+                default = Some(u64::from_le_bytes(item[0..8].try_into().unwrap()));
+            }
+            Ok(())
+        },
+    )?;
+
+    // Without a default dir item, the top-level subvolume is the default
+    Ok(default.unwrap_or(btrfs::BTRFS_FS_TREE_OBJECTID.into()))
+}
+
+/// Call `f` with the offset and contents of every item of type `item_type` for `objectid` in the
+/// root tree
+fn search(
+    fd: impl AsFd,
+    objectid: u64,
+    item_type: u32,
+    mut f: impl FnMut(u64, &[u8]) -> io::Result<()>,
+) -> io::Result<()> {
+    const HEADER_LEN: usize = mem::size_of::<btrfs::btrfs_ioctl_search_header>();
+
+    let fd = fd.as_fd();
+    let mut min_offset = 0;
+    loop {
+        // SAFETY: btrfs_ioctl_search_args is plain old data, so all zeroes is valid
+        let mut args: btrfs::btrfs_ioctl_search_args = unsafe { mem::zeroed() };
+        args.key.tree_id = btrfs::BTRFS_ROOT_TREE_OBJECTID.into();
+        args.key.min_objectid = objectid;
+        args.key.max_objectid = objectid;
+        args.key.min_type = item_type;
+        args.key.max_type = item_type;
+        args.key.min_offset = min_offset;
+        args.key.max_offset = u64::MAX;
+        args.key.max_transid = u64::MAX;
+        args.key.nr_items = 4096;
+
+        // SAFETY: TREE_SEARCH takes a btrfs_ioctl_search_args and fills in its buffer
+        unsafe { ioctl(fd, Updater::<TREE_SEARCH, _>::new(&mut args))? };
+        if args.key.nr_items == 0 {
+            return Ok(());
+        }
+
+        let buf = args.buf.map(|b| b as u8);
+        let mut items = &buf[..];
+        for _ in 0..args.key.nr_items {
+            let Some((header, rest)) = items.split_at_checked(HEADER_LEN) else {
+                return Err(Errno::OVERFLOW.into());
+            };
+            // SAFETY: the kernel wrote a btrfs_ioctl_search_header here
+            let header = unsafe {
+                header
+                    .as_ptr()
+                    .cast::<btrfs::btrfs_ioctl_search_header>()
+                    .read_unaligned()
+            };
+            let Some((item, rest)) = rest.split_at_checked(header.len as usize) else {
+                return Err(Errno::OVERFLOW.into());
+            };
+            items = rest;
+
+            if header.type_ == item_type {
+                f(header.offset, item)?;
+            }
+
+            match header.offset.checked_add(1) {
+                Some(next) => min_offset = next,
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Get the path of inode `objectid` relative to the root of subvolume `treeid`
+fn ino_lookup(fd: impl AsFd, treeid: u64, objectid: u64) -> io::Result<PathBuf> {
+    // SAFETY: btrfs_ioctl_ino_lookup_args is plain old data, so all zeroes is valid
+    let mut args: btrfs::btrfs_ioctl_ino_lookup_args = unsafe { mem::zeroed() };
+    args.treeid = treeid;
+    args.objectid = objectid;
+
+    // SAFETY: INO_LOOKUP takes a btrfs_ioctl_ino_lookup_args and fills in its name
+    unsafe { ioctl(fd, Updater::<INO_LOOKUP, _>::new(&mut args))? };
+
+    let name = args.name.map(|b| b as u8);
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    Ok(PathBuf::from(OsStr::from_bytes(&name[..len])))
+}
+
+/// Create an empty subvolume at `path`. Its parent directory must already exist.
+pub fn create_subvolume(path: &Path) -> io::Result<()> {
+    let (parent, name) = split(path)?;
+    let args = vol_args_v2(name)?;
+    // SAFETY: SUBVOL_CREATE_V2 takes a btrfs_ioctl_vol_args_v2
+    unsafe { ioctl(parent, Setter::<SUBVOL_CREATE_V2, _>::new(args))? };
+    Ok(())
+}
+
+/// Snapshot the subvolume at `source` to `dest`. The parent directory of `dest` must already
+/// exist.
+pub fn snapshot(source: &Path, dest: &Path, read_only: bool) -> io::Result<()> {
+    let source = File::open(source)?;
+    let (parent, name) = split(dest)?;
+
+    let mut args = vol_args_v2(name)?;
+    args.fd = source.as_raw_fd().into();
+    if read_only {
+        args.flags |= u64::from(btrfs::BTRFS_SUBVOL_RDONLY);
+    }
+
+    // SAFETY: SNAP_CREATE_V2 takes a btrfs_ioctl_vol_args_v2 with an open subvolume fd
+    unsafe { ioctl(parent, Setter::<SNAP_CREATE_V2, _>::new(args))? };
+    Ok(())
+}
+
+/// Delete the subvolume at `path`
+///
+/// This fails with `ENOTEMPTY` if the subvolume contains other subvolumes.
+pub fn destroy_subvolume(path: &Path) -> io::Result<()> {
+    let (parent, name) = split(path)?;
+    let args = vol_args_v2(name)?;
+    // SAFETY: SNAP_DESTROY_V2 takes a btrfs_ioctl_vol_args_v2
+    unsafe { ioctl(parent, Setter::<SNAP_DESTROY_V2, _>::new(args))? };
+    Ok(())
+}
+
+/// Wait for the space used by deleted subvolumes to be freed.
+///
+/// Kernels before 6.8 can't wait for the cleaner, so this only commits the current transaction
+/// there, and some space may still be freed later.
+pub fn wait_for_cleaner(fd: impl AsFd) -> io::Result<()> {
+    let args = btrfs::btrfs_ioctl_subvol_wait {
+        subvolid: 0,
+        mode: SUBVOL_SYNC_WAIT_FOR_QUEUED,
+        count: 0,
+    };
+    // SAFETY: SUBVOL_SYNC_WAIT takes a btrfs_ioctl_subvol_wait
+    match unsafe { ioctl(&fd, Setter::<SUBVOL_SYNC_WAIT, _>::new(args)) } {
+        Ok(()) => Ok(()),
+        Err(Errno::NOTTY | Errno::INVAL) => {
+            log::debug!("kernel cannot wait for the subvolume cleaner, syncing instead");
+            // SAFETY: SYNC takes no argument
+            unsafe { ioctl(&fd, NoArg::<SYNC>::new())? };
+            Ok(())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Register `device` as a member of a multi-device filesystem, so it can be mounted
+pub fn scan_device(device: &Path) -> io::Result<()> {
+    let args = vol_args(device)?;
+    // SAFETY: SCAN_DEV takes a btrfs_ioctl_vol_args with a device path
+    unsafe { ioctl(control()?, Setter::<SCAN_DEV, _>::new(args))? };
+    Ok(())
+}
+
+/// Check whether every member of the filesystem on `device` has been registered
+pub fn devices_ready(device: &Path) -> io::Result<bool> {
+    let mut args = vol_args(device)?;
+    // SAFETY: DEVICES_READY takes a btrfs_ioctl_vol_args with a device path
+    let missing = unsafe { ioctl(control()?, DevicesReady(&mut args))? };
+    Ok(missing == 0)
+}
+
+/// DEVICES_READY reports missing devices through its return value, which none of the rustix
+/// patterns expose
+struct DevicesReady<'a>(&'a mut btrfs::btrfs_ioctl_vol_args);
+
+// SAFETY: the opcode matches the argument type, and the return value is passed through as is
+unsafe impl Ioctl for DevicesReady<'_> {
+    type Output = IoctlOutput;
+
+    const IS_MUTATING: bool = true;
+
+    fn opcode(&self) -> Opcode {
+        DEVICES_READY
+    }
+
+    fn as_ptr(&mut self) -> *mut c_void {
+        ptr::from_mut(self.0).cast()
+    }
+
+    unsafe fn output_from_ptr(
+        out: IoctlOutput,
+        _: *mut c_void,
+    ) -> rustix::io::Result<Self::Output> {
+        Ok(out)
+    }
+}
+
+fn control() -> io::Result<File> {
+    OpenOptions::new().read(true).write(true).open(CONTROL)
+}
+
+fn vol_args(path: &Path) -> io::Result<btrfs::btrfs_ioctl_vol_args> {
+    // SAFETY: btrfs_ioctl_vol_args is plain old data, so all zeroes is valid
+    let mut args: btrfs::btrfs_ioctl_vol_args = unsafe { mem::zeroed() };
+
+    let path = path.as_os_str().as_bytes();
+    if path.len() >= args.name.len() {
+        return Err(Errno::NAMETOOLONG.into());
+    }
+    if path.contains(&0) {
+        return Err(Errno::INVAL.into());
+    }
+    for (dst, src) in args.name.iter_mut().zip(path) {
+        *dst = *src as _;
+    }
+
+    Ok(args)
+}
+
+/// Open the parent directory of `path` and get the final component's name
+fn split(path: &Path) -> io::Result<(File, &OsStr)> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::from(Errno::INVAL))?;
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    Ok((File::open(parent)?, name))
+}
+
+fn vol_args_v2(name: &OsStr) -> io::Result<btrfs::btrfs_ioctl_vol_args_v2> {
+    // SAFETY: btrfs_ioctl_vol_args_v2 is plain old data, so all zeroes is valid
+    let mut args: btrfs::btrfs_ioctl_vol_args_v2 = unsafe { mem::zeroed() };
+
+    let mut buf = [0; btrfs::BTRFS_SUBVOL_NAME_MAX as usize + 1];
+    let name = name.as_bytes();
+    if name.len() >= buf.len() {
+        return Err(Errno::NAMETOOLONG.into());
+    }
+    if name.contains(&0) {
+        return Err(Errno::INVAL.into());
+    }
+    for (dst, src) in buf.iter_mut().zip(name) {
+        *dst = *src as _;
+    }
+    args.__bindgen_anon_2.name = buf;
+
+    Ok(args)
+}
+
+fn timespec(ts: btrfs::btrfs_ioctl_timespec) -> Option<SystemTime> {
+    (ts.sec != 0).then(|| SystemTime::UNIX_EPOCH + Duration::new(ts.sec, ts.nsec))
+}
@@ -0,0 +1,143 @@
+//! Command line arguments
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+const HELP: &str = "\
+Back up and wipe btrfs root volumes, for systems that start each boot from a clean slate
+
+Usage: demolition [OPTIONS] [COMMAND]
+
+Commands:
+  run                      Back up and wipe every volume, then create fresh ones (default)
+  list [VOLUME]            List backups and whether the retention policy keeps them
+  prune [VOLUME]           Remove backups the retention policy and min-free don't keep, without
+                           wiping anything
+  restore VOLUME BACKUP    Back up the volume, then replace it with a writable snapshot of BACKUP
+  diff VOLUME [OLD [NEW]]  List files that differ between two backups, or between a backup and
+                           the volume. Defaults to the newest backup and the volume.
+  status                   Show the filesystem and the state of every volume
+  check                    Check the device and every volume without changing anything
+
+Options:
+  -c, --config FILE  Read settings from FILE instead of DEMOLITION_CONFIG or /etc/demolition.toml
+  -d, --device SPEC  Use the filesystem on SPEC: a path, or UUID=, LABEL=, PARTUUID=, PARTLABEL=
+  -p, --path DIR     Use the top-level subvolume already mounted at DIR
+  -n, --dry-run      Show what run, prune or restore would do, without changing anything
+      --json         Show the dry run as JSON
+  -h, --help         Show this help
+
+Every setting can also be set with DEMOLITION_* env vars and demolition.* kernel parameters.
+Logging is controlled by DEMOLITION_LOG, such as DEMOLITION_LOG=info.
+
+Exit codes:
+  0  success
+  1  invalid arguments or config
+  2  failed
+  3  device not found
+  4  check found problems
+";
+
+#[derive(Debug)]
+pub enum Command {
+    Run,
+    List {
+        volume: Option<String>,
+    },
+    Prune {
+        volume: Option<String>,
+    },
+    Restore {
+        volume: String,
+        backup: String,
+    },
+    Diff {
+        volume: String,
+        /// Backup to compare from, instead of the newest one
+        old: Option<String>,
+        /// Backup to compare to, instead of the volume itself
+        new: Option<String>,
+    },
+    Status,
+    Check,
+}
+
+#[derive(Debug)]
+pub struct Args {
+    pub command: Command,
+    /// Config file to read instead of the default one
+    pub config: Option<PathBuf>,
+    /// Settings given as options, which take priority over every other source
+    pub overrides: HashMap<&'static str, String>,
+    /// Show dry runs as JSON
+    pub json: bool,
+}
+
+/// Parse the arguments, printing the help and exiting if it was asked for
+pub fn parse() -> Result<Args, lexopt::Error> {
+    use lexopt::prelude::*;
+
+    let mut parser = lexopt::Parser::from_env();
+    let mut config = None;
+    let mut overrides = HashMap::new();
+    let mut json = false;
+    let mut positional = Vec::new();
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Short('c') | Long("config") => config = Some(parser.value()?.into()),
+            Short('d') | Long("device") => {
+                overrides.insert("device", parser.value()?.string()?);
+            }
+            Short('p') | Long("path") => {
+                overrides.insert("path", parser.value()?.string()?);
+            }
+            Short('n') | Long("dry-run") => {
+                overrides.insert("dry-run", "true".to_owned());
+            }
+            Long("json") => json = true,
+            Short('h') | Long("help") => {
+                print!("{HELP}");
+                std::process::exit(0);
+            }
+            Value(value) => positional.push(value.string()?),
+            _ => return Err(arg.unexpected()),
+        }
+    }
+
+    if overrides.contains_key("device") && overrides.contains_key("path") {
+        return Err("--device and --path can't be used together".into());
+    }
+
+    let mut positional = positional.into_iter();
+    let command = positional.next();
+    let mut next = || positional.next();
+    let missing = |what: &str| lexopt::Error::from(format!("missing {what}"));
+    let command = match command.as_deref() {
+        None | Some("run") => Command::Run,
+        Some("list") => Command::List { volume: next() },
+        Some("prune") => Command::Prune { volume: next() },
+        Some("restore") => Command::Restore {
+            volume: next().ok_or_else(|| missing("volume to restore"))?,
+            backup: next().ok_or_else(|| missing("backup to restore"))?,
+        },
+        Some("diff") => Command::Diff {
+            volume: next().ok_or_else(|| missing("volume to compare"))?,
+            old: next(),
+            new: next(),
+        },
+        Some("status") => Command::Status,
+        Some("check") => Command::Check,
+        Some(command) => return Err(format!("unknown command {command:?}").into()),
+    };
+
+    if let Some(extra) = next() {
+        return Err(format!("unexpected argument {extra:?}").into());
+    }
+
+    Ok(Args {
+        command,
+        config,
+        overrides,
+        json,
+    })
+}
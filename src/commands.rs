@@ -0,0 +1,299 @@
+//! Subcommands other than `run`, mostly for looking after backups from a booted system.
+
+use std::ffi::OsStr;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::{
+    EXIT_ENV, EXIT_PROBLEMS, Error, backups, bail, check_volume, config, diff, open, plan, space,
+    subvolume, superblock, unwrap,
+};
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S UTC";
+
+/// Show every backup and what the retention policy would do with it
+pub fn list(config: &config::Config, volume: Option<&str>) -> Result<(), Error> {
+    let volumes = select(config, volume)?;
+    let (mount, fsid) = open(config)?;
+    let now = SystemTime::now().into();
+
+    for volume in volumes {
+        let backups = plan::decide(
+            volume,
+            crate::list_backups(mount.path(), volume, &fsid)?,
+            now,
+        );
+        println!("{} ({}):", volume.name, volume.backup_dir.display());
+
+        let names: Vec<_> = backups
+            .iter()
+            .map(|(backup, _)| name(&backup.path))
+            .collect();
+        let width = names.iter().map(|name| name.chars().count()).max();
+        for ((backup, keep), name) in backups.iter().zip(names.iter()) {
+            let keep = match keep {
+                Some(keep) => format!("keep ({keep})"),
+                None => "remove".to_owned(),
+            };
+            print!(
+                "  {name:width$}  {}  {keep}",
+                backup.created.format(TIME_FORMAT),
+                width = width.unwrap_or_default()
+            );
+            if backup.source != backups::Source::Name {
+                print!(", dated by its {}", backup.source);
+            }
+            println!();
+        }
+    }
+
+    unwrap!(mount.unmount(), "umount failed: {err}");
+    Ok(())
+}
+
+/// Apply the retention policy and minimum free space without wiping anything
+pub fn prune(config: &config::Config, volume: Option<&str>) -> Result<(), Error> {
+    let volumes = select(config, volume)?;
+    let (mount, fsid) = open(config)?;
+    let mount_dir = mount.path();
+    let now = SystemTime::now().into();
+
+    let mut planned = Vec::new();
+    for volume in volumes {
+        planned.push(plan::Volume {
+            name: volume.name.clone(),
+            back_up: None,
+            backups: plan::prune(mount_dir, volume, &fsid, now, None)?,
+            create: None,
+        });
+    }
+
+    let plan = plan::Plan {
+        root: mount_dir.to_owned(),
+        free_space: plan::free_space(mount_dir, &planned, config),
+        volumes: planned,
+    };
+    crate::finish(config, mount, &plan)
+}
+
+/// Back up `volume` like a normal run, then replace it with a writable snapshot of `backup`
+pub fn restore(config: &config::Config, volume: &str, backup: &str) -> Result<(), Error> {
+    let volume = find(config, volume)?;
+    let (mount, fsid) = open(config)?;
+    let mount_dir = mount.path();
+
+    let backup = backup_path(mount_dir, volume, &fsid, backup)?;
+    let tree = unwrap!(
+        subvolume::Tree::scan(&backup),
+        "failed to find subvolumes in backup: {err}"
+    );
+    // Snapshots don't include nested subvolumes
+    for nested in &tree.nested {
+        log::warn!(
+            "nested subvolume is not part of the snapshot and won't be restored: '{}'",
+            nested.path.display()
+        );
+    }
+
+    let kind = check_volume(mount_dir, volume, &fsid)?;
+    let plan = plan::Plan {
+        root: mount_dir.to_owned(),
+        volumes: vec![plan::Volume {
+            name: volume.name.clone(),
+            back_up: plan::back_up(mount_dir, volume, kind)?,
+            backups: Vec::new(),
+            create: Some(plan::Create {
+                path: mount_dir.join(&volume.subvolume),
+                template: Some(backup),
+            }),
+        }],
+        free_space: None,
+    };
+    crate::finish(config, mount, &plan)
+}
+
+/// List the files that differ between two backups, or between a backup and the volume itself
+pub fn diff(
+    config: &config::Config,
+    volume: &str,
+    old: Option<&str>,
+    new: Option<&str>,
+) -> Result<(), Error> {
+    let volume = find(config, volume)?;
+    let (mount, fsid) = open(config)?;
+    let mount_dir = mount.path();
+
+    let old = match old {
+        Some(old) => backup_path(mount_dir, volume, &fsid, old)?,
+        None => match crate::list_backups(mount_dir, volume, &fsid)?
+            .into_iter()
+            .next()
+        {
+            Some(newest) => newest.path,
+            None => bail!("volume {} has no backups", volume.name),
+        },
+    };
+    let new = match new {
+        Some(new) => backup_path(mount_dir, volume, &fsid, new)?,
+        None => mount_dir.join(&volume.subvolume),
+    };
+
+    let compared = diff::diff(&old, &new, &mut |change, path| {
+        println!("{change} {}", path.display());
+    });
+    if let Err(err) = compared {
+        bail!(
+            "failed to compare '{}' and '{}': {err}",
+            old.display(),
+            new.display()
+        );
+    }
+
+    unwrap!(mount.unmount(), "umount failed: {err}");
+    Ok(())
+}
+
+/// Show the free space on the filesystem, and what is in every volume
+pub fn status(config: &config::Config) -> Result<(), Error> {
+    let (mount, fsid) = open(config)?;
+    let mount_dir = mount.path();
+
+    println!("filesystem {}", superblock::format_uuid(&fsid));
+    match space::space(mount_dir) {
+        Ok(space) => {
+            let min_free = if config.min_free.is_disabled() {
+                String::new()
+            } else if config.min_free.is_met(space) {
+                format!(", minimum is {}", config.min_free)
+            } else {
+                format!(", below the minimum of {}", config.min_free)
+            };
+            println!(
+                "{} of {} bytes available{min_free}",
+                space.available, space.total
+            );
+        }
+        Err(err) => log::warn!("failed to get free space of filesystem: {err}"),
+    }
+
+    for volume in &config.volumes {
+        let state = match subvolume::kind(&mount_dir.join(&volume.subvolume), &fsid) {
+            Ok(kind) => kind.to_string(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => "missing".to_owned(),
+            Err(err) => format!("unknown: {err}"),
+        };
+        println!(
+            "{}: '{}' is {state}",
+            volume.name,
+            volume.subvolume.display()
+        );
+
+        let backups = match crate::list_backups(mount_dir, volume, &fsid) {
+            Ok(backups) => backups,
+            Err(err) => {
+                println!("  {}", err.message);
+                continue;
+            }
+        };
+        let pinned = backups.iter().filter(|backup| backup.pin.is_some()).count();
+        println!(
+            "  {} backups in '{}', {pinned} pinned",
+            backups.len(),
+            volume.backup_dir.display()
+        );
+        if let (Some(newest), Some(oldest)) = (backups.first(), backups.last()) {
+            println!(
+                "  newest {}, oldest {}",
+                newest.created.format(TIME_FORMAT),
+                oldest.created.format(TIME_FORMAT)
+            );
+        }
+    }
+
+    unwrap!(mount.unmount(), "umount failed: {err}");
+    Ok(())
+}
+
+/// Check that a run would be able to wipe every volume
+pub fn check(config: &config::Config) -> Result<(), Error> {
+    let (mount, fsid) = open(config)?;
+    let mount_dir = mount.path();
+
+    let mut problems = 0;
+    for volume in &config.volumes {
+        let checked = check_volume(mount_dir, volume, &fsid)
+            .and_then(|_| crate::list_backups(mount_dir, volume, &fsid));
+        match checked {
+            Ok(_) => println!("ok: {}", volume.name),
+            Err(err) => {
+                println!("problem: {}", err.message);
+                problems += 1;
+            }
+        }
+    }
+
+    unwrap!(mount.unmount(), "umount failed: {err}");
+    if problems > 0 {
+        return Err(Error {
+            message: format!(
+                "{problems} of {} volumes have problems",
+                config.volumes.len()
+            ),
+            code: EXIT_PROBLEMS,
+        });
+    }
+    Ok(())
+}
+
+/// Every configured volume, or only the one called `name`
+fn select<'a>(
+    config: &'a config::Config,
+    name: Option<&str>,
+) -> Result<Vec<&'a config::Volume>, Error> {
+    match name {
+        Some(name) => Ok(vec![find(config, name)?]),
+        None => Ok(config.volumes.iter().collect()),
+    }
+}
+
+fn find<'a>(config: &'a config::Config, name: &str) -> Result<&'a config::Volume, Error> {
+    config
+        .volumes
+        .iter()
+        .find(|volume| volume.name == name)
+        .ok_or_else(|| Error {
+            message: format!("no volume called {name}"),
+            code: EXIT_ENV,
+        })
+}
+
+/// Find the backup of `volume` called `name`
+fn backup_path(
+    mount_dir: &Path,
+    volume: &config::Volume,
+    fsid: &[u8; 16],
+    name: &str,
+) -> Result<PathBuf, Error> {
+    if Path::new(name).file_name() != Some(OsStr::new(name)) {
+        return Err(Error {
+            message: format!("expected the name of a backup, not a path: {name}"),
+            code: EXIT_ENV,
+        });
+    }
+
+    let path = mount_dir.join(&volume.backup_dir).join(name);
+    match subvolume::kind(&path, fsid) {
+        Ok(subvolume::Kind::Subvolume) => Ok(path),
+        Ok(kind) => bail!("backup {name} of {} is {kind}", volume.name),
+        Err(err) => bail!("failed to find backup {name} of {}: {err}", volume.name),
+    }
+}
+
+/// The name of a backup, as it is given on the command line
+fn name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+}
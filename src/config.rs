@@ -0,0 +1,818 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{fs, io};
+
+use toml::{Table, Value};
+
+use crate::subvolume::PlainDirs;
+use crate::{backups, cli, device, mount, retention, space, superblock};
+
+/// Used when neither `--config` nor `DEMOLITION_CONFIG` is set. It's fine for this one not to exist.
+const DEFAULT_PATH: &str = "/etc/demolition.toml";
+
+/// Keys that only make sense for the whole run
+const GLOBAL_KEYS: &[&str] = &[
+    "path",
+    "device",
+    "device-timeout",
+    "member-timeout",
+    "degraded",
+    "fsid",
+    "mount-dir",
+    "mount-options",
+    "private-namespace",
+    "min-free",
+    "min-free-keep",
+    "dry-run",
+    "volumes",
+];
+
+/// Keys for the single volume used when no volumes are configured
+const LEGACY_KEYS: &[&str] = &["root-volume", "backup-dir", "template"];
+
+/// Keys that can be set for all volumes, and overridden for each one
+const SHARED_KEYS: &[&str] = &[
+    "backup-format",
+    "backup-mode",
+    "snapshot-nested",
+    "plain-dirs",
+    "keep-count",
+    "keep-duration",
+    "keep-hourly",
+    "keep-daily",
+    "keep-weekly",
+    "keep-monthly",
+];
+
+const VOLUME_KEYS: &[&str] = &["volume", "backup-dir", "template"];
+
+/// Keys that can only be set on the kernel command line, for a single boot
+const CMDLINE_KEYS: &[&str] = &["skip"];
+
+#[derive(Debug)]
+pub struct Config {
+    pub target: Target,
+    /// How long to wait for the device to show up
+    pub device_timeout: Duration,
+    /// How long to wait for the other devices of a multi-device filesystem
+    pub member_timeout: Duration,
+    /// Whether to mount anyway if some devices are missing
+    pub degraded: bool,
+    /// UUID the filesystem on `device` must have
+    pub fsid: Option<[u8; 16]>,
+    /// Only used on kernels without the new mount API
+    pub mount_dir: PathBuf,
+    pub mount_options: mount::Options,
+    /// Whether to mount in a private mount namespace
+    pub private_namespace: bool,
+    pub min_free: space::MinFree,
+    pub min_free_keep: u16,
+    /// Leave everything alone this time
+    pub skip: bool,
+    /// Show what would be done instead of doing it
+    pub dry_run: bool,
+    /// Show dry runs as JSON instead of text
+    pub json: bool,
+    pub volumes: Vec<Volume>,
+}
+
+/// Where to find the filesystem
+#[derive(Debug)]
+pub enum Target {
+    /// Mount this device, or reuse an existing mount of its top-level subvolume
+    Device(device::Spec),
+    /// Use this directory as is, without mounting anything
+    Path(PathBuf),
+}
+
+/// A subvolume that is wiped on every run
+#[derive(Debug, Clone)]
+pub struct Volume {
+    pub name: String,
+    /// Relative to the top level of the filesystem
+    pub subvolume: PathBuf,
+    /// Relative to the top level of the filesystem
+    pub backup_dir: PathBuf,
+    pub backup_format: String,
+    pub backup_mode: backups::Mode,
+    pub snapshot_nested: backups::Nested,
+    /// Relative to the top level of the filesystem
+    pub template: Option<PathBuf>,
+    pub plain_dirs: PlainDirs,
+    pub retention: retention::Policy,
+}
+
+impl Config {
+    /// Read the config file, with `DEMOLITION_*` env vars, `demolition.*` kernel parameters, and
+    /// then command line options overriding its keys
+    ///
+    /// The file is `--config`, `DEMOLITION_CONFIG`, or `/etc/demolition.toml` if that exists. Each
+    /// key has a matching env var and kernel parameter, so `keep-count` can be overridden by
+    /// `DEMOLITION_KEEP_COUNT` or `demolition.keep-count=`, and a volume's by
+    /// `demolition.<name>.keep-count=`. A kernel parameter without a value means `true`.
+    /// `demolition.skip` only exists as a kernel parameter.
+    ///
+    /// Volumes are the tables under `[volumes.<name>]`, or the comma separated names in
+    /// `DEMOLITION_VOLUMES`. Each volume can override the shared settings, in its table or with
+    /// `DEMOLITION_<NAME>_<SETTING>`, where `<NAME>` is the uppercased name with anything other
+    /// than letters and digits replaced by `_`. These only win over the shared setting from the
+    /// same source, so `demolition.keep-count=` also applies to volumes whose tables set
+    /// `keep-count`. A volume's `volume` defaults to its name and its
+    /// `backup-dir` to `<name>-backups`. Without any volumes, a single one is configured by
+    /// `root-volume`, `backup-dir`, and `template`.
+    ///
+    /// Every problem is reported before exiting.
+    pub fn load(args: &cli::Args) -> Self {
+        let mut reader = Reader::new(args);
+        let mut config = reader.read();
+        config.json = args.json;
+        if reader.errors.is_empty() {
+            return config;
+        }
+
+        for err in &reader.errors {
+            log::error!("{err}");
+        }
+        std::process::exit(crate::EXIT_ENV);
+    }
+}
+
+/// Looks up settings in the env and the config file, collecting every error
+struct Reader {
+    path: PathBuf,
+    file: Table,
+    /// `demolition.*` kernel parameters without the prefix
+    cmdline: HashMap<String, Option<String>>,
+    /// Global keys set by command line options
+    overrides: HashMap<&'static str, String>,
+    errors: Vec<String>,
+}
+
+impl Reader {
+    fn new(args: &cli::Args) -> Self {
+        let mut reader = Self {
+            path: PathBuf::from(DEFAULT_PATH),
+            file: Table::new(),
+            cmdline: cmdline(),
+            overrides: args.overrides.clone(),
+            errors: Vec::new(),
+        };
+
+        let explicit = args
+            .config
+            .clone()
+            .or_else(|| std::env::var_os("DEMOLITION_CONFIG").map(PathBuf::from));
+        if let Some(path) = &explicit {
+            reader.path.clone_from(path);
+        }
+
+        match fs::read_to_string(&reader.path) {
+            Ok(file) => match file.parse() {
+                Ok(file) => reader.file = file,
+                Err(err) => reader.error(format!(
+                    "invalid config file {}: {err}",
+                    reader.path.display()
+                )),
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound && explicit.is_none() => {
+                log::debug!("no config file at {DEFAULT_PATH}");
+            }
+            Err(err) => reader.error(format!(
+                "failed to read config file {}: {err}",
+                reader.path.display()
+            )),
+        }
+
+        reader.check_keys();
+        reader
+    }
+
+    fn read(&mut self) -> Config {
+        let volumes = match self.volume_names() {
+            None => vec![Volume {
+                name: "root".to_owned(),
+                subvolume: self.required(None, "root-volume", "root", parse_path),
+                backup_dir: self.required(None, "backup-dir", "root-backups", parse_path),
+                template: self.opt(None, "template", parse_path),
+                ..self.shared(None)
+            }],
+            Some(names) => names.iter().map(|name| self.volume(name)).collect(),
+        };
+        self.validate(&volumes);
+
+        // Whichever of device and path was set with the higher priority wins, and path if both
+        // were set in the same place
+        let path = if self.priority("path") >= self.priority("device") {
+            self.opt(None, "path", parse_path)
+        } else {
+            None
+        };
+        let target = match path {
+            Some(path) => Target::Path(path),
+            None => {
+                Target::Device(self.required(None, "device", "/dev/mapper/crypted", str::parse))
+            }
+        };
+
+        Config {
+            target,
+            device_timeout: self.get(None, "device-timeout", "0", humantime::parse_duration),
+            member_timeout: self.get(None, "member-timeout", "0", humantime::parse_duration),
+            degraded: self.get(None, "degraded", "false", parse_bool),
+            fsid: self.opt(None, "fsid", superblock::parse_uuid),
+            mount_dir: self.get(None, "mount-dir", "/run/demolition", parse_path),
+            mount_options: self.get(
+                None,
+                "mount-options",
+                "noatime,nodev,noexec,nosuid",
+                str::parse,
+            ),
+            private_namespace: self.get(None, "private-namespace", "false", parse_bool),
+            min_free: self.get(None, "min-free", "0", str::parse),
+            min_free_keep: self.get(None, "min-free-keep", "1", str::parse),
+            skip: self.cmdline_flag("skip"),
+            dry_run: self.get(None, "dry-run", "false", parse_bool),
+            json: false,
+            volumes,
+        }
+    }
+
+    fn volume(&mut self, name: &str) -> Volume {
+        let scope = Some(name);
+        Volume {
+            name: name.to_owned(),
+            subvolume: self.get(scope, "volume", name, parse_path),
+            backup_dir: self.get(scope, "backup-dir", &format!("{name}-backups"), parse_path),
+            template: self.opt(scope, "template", parse_path),
+            ..self.shared(scope)
+        }
+    }
+
+    /// The settings in [`SHARED_KEYS`], for `volume` if given
+    fn shared(&mut self, volume: Option<&str>) -> Volume {
+        Volume {
+            name: String::new(),
+            subvolume: PathBuf::new(),
+            backup_dir: PathBuf::new(),
+            backup_format: self.required(volume, "backup-format", "%Y%m%d_%H%M%S", parse_string),
+            backup_mode: self.get(volume, "backup-mode", "rename", str::parse),
+            snapshot_nested: self.get(volume, "snapshot-nested", "rename", str::parse),
+            template: None,
+            plain_dirs: self.get(volume, "plain-dirs", "skip", str::parse),
+            retention: retention::Policy {
+                last: self.required(volume, "keep-count", "1", str::parse),
+                within: self.required(volume, "keep-duration", "1day", humantime::parse_duration),
+                hourly: self.get(volume, "keep-hourly", "0", str::parse),
+                daily: self.get(volume, "keep-daily", "0", str::parse),
+                weekly: self.get(volume, "keep-weekly", "0", str::parse),
+                monthly: self.get(volume, "keep-monthly", "0", str::parse),
+            },
+        }
+    }
+
+    /// Names from `DEMOLITION_VOLUMES`, or else the tables under `[volumes]`
+    fn volume_names(&mut self) -> Option<Vec<String>> {
+        let names: Vec<_> = if let Some(names) = self.opt(None, "volumes", parse_string) {
+            let names: Vec<_> = names
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(str::to_owned)
+                .collect();
+            if names.is_empty() {
+                self.error("no volumes configured in DEMOLITION_VOLUMES".to_owned());
+            }
+            names
+        } else {
+            let names: Vec<_> = self
+                .file
+                .get("volumes")?
+                .as_table()?
+                .keys()
+                .cloned()
+                .collect();
+            if names.is_empty() {
+                self.error(format!("no volumes configured in {}", self.path.display()));
+            }
+            names
+        };
+
+        // These only configure the single volume used without any others
+        for key in LEGACY_KEYS {
+            if self.file.contains_key(*key) {
+                self.error(format!(
+                    "{key} in {} is only used without volumes, set it in [volumes.<name>] instead",
+                    self.path.display()
+                ));
+            }
+        }
+        Some(names)
+    }
+
+    /// Report volumes that would step on each other
+    fn validate(&mut self, volumes: &[Volume]) {
+        for (i, a) in volumes.iter().enumerate() {
+            for b in &volumes[i + 1..] {
+                let conflict = if a.name == b.name {
+                    "name"
+                } else if a.subvolume == b.subvolume {
+                    "subvolume"
+                } else if a.backup_dir == b.backup_dir {
+                    "backup directory"
+                } else {
+                    continue;
+                };
+                self.error(format!(
+                    "volumes '{}' and '{}' have the same {conflict}",
+                    a.name, b.name
+                ));
+            }
+        }
+    }
+
+    /// Report keys in the config file that don't mean anything
+    fn check_keys(&mut self) {
+        let mut unknown = Vec::new();
+        for (key, value) in &self.file {
+            if key == "volumes" {
+                let Some(volumes) = value.as_table() else {
+                    unknown.push("volumes must be a table of volumes".to_owned());
+                    continue;
+                };
+                for (name, volume) in volumes {
+                    let Some(volume) = volume.as_table() else {
+                        unknown.push(format!("volumes.{name} must be a table"));
+                        continue;
+                    };
+                    for key in volume.keys() {
+                        if !VOLUME_KEYS.contains(&key.as_str())
+                            && !SHARED_KEYS.contains(&key.as_str())
+                        {
+                            unknown.push(format!("unknown key volumes.{name}.{key}"));
+                        }
+                    }
+                }
+            } else if ![GLOBAL_KEYS, LEGACY_KEYS, SHARED_KEYS]
+                .iter()
+                .any(|keys| keys.contains(&key.as_str()))
+            {
+                unknown.push(format!("unknown key {key}"));
+            }
+        }
+
+        for err in unknown {
+            self.error(format!("{err} in {}", self.path.display()));
+        }
+
+        // Only a warning, so a typo can't stop the boot from going ahead as configured
+        for param in self.cmdline.keys() {
+            if !param.contains('.')
+                && ![GLOBAL_KEYS, LEGACY_KEYS, SHARED_KEYS, CMDLINE_KEYS]
+                    .iter()
+                    .any(|keys| keys.contains(&param.as_str()))
+            {
+                log::warn!("ignoring unknown kernel parameter demolition.{param}");
+            }
+        }
+    }
+
+    /// Read `key`, using `default` if it isn't set
+    fn get<T, E: fmt::Display>(
+        &mut self,
+        volume: Option<&str>,
+        key: &str,
+        default: &str,
+        parse: impl Fn(&str) -> Result<T, E>,
+    ) -> T {
+        self.opt(volume, key, &parse)
+            .unwrap_or_else(|| parse_default(key, default, parse))
+    }
+
+    /// Read `key`, which must be set
+    fn required<T, E: fmt::Display>(
+        &mut self,
+        volume: Option<&str>,
+        key: &str,
+        placeholder: &str,
+        parse: impl Fn(&str) -> Result<T, E>,
+    ) -> T {
+        if let Some(value) = self.opt(volume, key, &parse) {
+            return value;
+        }
+
+        if self.lookup(volume, key).is_none() {
+            let err = match volume {
+                None => format!(
+                    "{key} is not set in {} or {}",
+                    self.path.display(),
+                    env_var(volume, key)
+                ),
+                Some(name) => format!(
+                    "{key} is not set for volume {name} or for all volumes, in {} or {}",
+                    self.path.display(),
+                    env_var(volume, key)
+                ),
+            };
+            self.error(err);
+        }
+        // Stands in for the missing or invalid value, since the config isn't used then
+        parse_default(key, placeholder, parse)
+    }
+
+    /// Read `key`, if it is set
+    fn opt<T, E: fmt::Display>(
+        &mut self,
+        volume: Option<&str>,
+        key: &str,
+        parse: impl Fn(&str) -> Result<T, E>,
+    ) -> Option<T> {
+        let (origin, value) = self.lookup(volume, key)?;
+        match parse(&value) {
+            Ok(value) => Some(value),
+            Err(err) => {
+                self.error(format!("invalid format for {origin}: {err}"));
+                None
+            }
+        }
+    }
+
+    /// Where the global setting `key` would be read from, ranked like [`Reader::lookup`] ranks
+    /// them, or `None` if it isn't set anywhere
+    fn priority(&self, key: &str) -> Option<u8> {
+        if self.overrides.contains_key(key) {
+            Some(3)
+        } else if self.cmdline.contains_key(key) {
+            Some(2)
+        } else if std::env::var_os(env_var(None, key)).is_some() {
+            Some(1)
+        } else if self.file.contains_key(key) {
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    /// Read a kernel parameter that is only a flag
+    ///
+    /// An invalid value is only a warning, so a typo can't stop the boot from going ahead.
+    fn cmdline_flag(&self, key: &str) -> bool {
+        let Some(value) = self.cmdline.get(key) else {
+            return false;
+        };
+        match value.as_deref().map_or(Ok(true), parse_bool) {
+            Ok(value) => value,
+            Err(err) => {
+                log::warn!("ignoring demolition.{key} on the kernel command line: {err}");
+                false
+            }
+        }
+    }
+
+    /// Find the raw value of `key`, and where it came from
+    ///
+    /// A shared key set for `volume` only wins over the one for all volumes if both are set in the
+    /// same place, so `demolition.keep-count=` still overrides `keep-count` in a volume's table.
+    fn lookup(&mut self, volume: Option<&str>, key: &str) -> Option<(String, String)> {
+        if volume.is_none()
+            && let Some(value) = self.overrides.get(key)
+        {
+            return Some((format!("--{key} on the command line"), value.clone()));
+        }
+
+        let scopes = match volume {
+            Some(_) if SHARED_KEYS.contains(&key) => vec![volume, None],
+            _ => vec![volume],
+        };
+
+        for &scope in &scopes {
+            let param = match scope {
+                None => key.to_owned(),
+                Some(volume) => format!("{volume}.{key}"),
+            };
+            if let Some(value) = self.cmdline.get(&param) {
+                let value = value.as_deref().unwrap_or("true").to_owned();
+                return Some((
+                    format!("demolition.{param} on the kernel command line"),
+                    value,
+                ));
+            }
+        }
+
+        for &scope in &scopes {
+            let var = env_var(scope, key);
+            if let Some(value) = std::env::var_os(&var) {
+                return match value.into_string() {
+                    Ok(value) => Some((var, value)),
+                    Err(_) => {
+                        self.error(format!("env var is not unicode: {var}"));
+                        None
+                    }
+                };
+            }
+        }
+
+        for &scope in &scopes {
+            let (table, name) = match scope {
+                None => (Some(&self.file), key.to_owned()),
+                Some(volume) => (
+                    self.file
+                        .get("volumes")
+                        .and_then(Value::as_table)
+                        .and_then(|volumes| volumes.get(volume))
+                        .and_then(Value::as_table),
+                    format!("volumes.{volume}.{key}"),
+                ),
+            };
+            let Some(value) = table.and_then(|table| table.get(key)) else {
+                continue;
+            };
+            let origin = format!("{name} in {}", self.path.display());
+            return match value {
+                Value::String(value) => Some((origin, value.clone())),
+                Value::Integer(value) => Some((origin, value.to_string())),
+                Value::Boolean(value) => Some((origin, value.to_string())),
+                // Only a table under [volumes], which check_keys has already looked at
+                Value::Table(_) if scope.is_none() && key == "volumes" => None,
+                _ => {
+                    self.error(format!(
+                        "invalid type for {origin}: expected a string, integer, or boolean"
+                    ));
+                    None
+                }
+            };
+        }
+        None
+    }
+
+    fn error(&mut self, err: String) {
+        // Settings for all volumes are read again for each one
+        if !self.errors.contains(&err) {
+            self.errors.push(err);
+        }
+    }
+}
+
+/// Parse the `demolition.*` parameters on the kernel command line
+fn cmdline() -> HashMap<String, Option<String>> {
+    match fs::read_to_string("/proc/cmdline") {
+        Ok(cmdline) => parse_cmdline(&cmdline),
+        Err(err) => {
+            log::debug!("failed to read /proc/cmdline: {err}");
+            HashMap::new()
+        }
+    }
+}
+
+fn parse_cmdline(cmdline: &str) -> HashMap<String, Option<String>> {
+    split_cmdline(cmdline)
+        .into_iter()
+        .filter_map(|param| {
+            let param = param.strip_prefix("demolition.")?;
+            Some(match param.split_once('=') {
+                Some((key, value)) => (key.to_owned(), Some(value.to_owned())),
+                None => (param.to_owned(), None),
+            })
+        })
+        .collect()
+}
+
+/// Split the kernel command line into parameters, which may have double quotes around spaces
+fn split_cmdline(cmdline: &str) -> Vec<String> {
+    let mut params = Vec::new();
+    let mut param = String::new();
+    let mut quoted = false;
+    for c in cmdline.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !param.is_empty() {
+                    params.push(std::mem::take(&mut param));
+                }
+            }
+            c => param.push(c),
+        }
+    }
+    if !param.is_empty() {
+        params.push(param);
+    }
+    params
+}
+
+/// The env var that overrides `key`, for `volume` if given
+fn env_var(volume: Option<&str>, key: &str) -> String {
+    let mut var = String::from("DEMOLITION_");
+    if let Some(volume) = volume {
+        var.extend(volume.chars().map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        }));
+        var.push('_');
+    }
+    var.extend(key.chars().map(|c| {
+        if c == '-' {
+            '_'
+        } else {
+            c.to_ascii_uppercase()
+        }
+    }));
+    var
+}
+
+fn parse_default<T, E: fmt::Display>(
+    key: &str,
+    default: &str,
+    parse: impl Fn(&str) -> Result<T, E>,
+) -> T {
+    match parse(default) {
+        Ok(value) => value,
+        Err(err) => panic!("invalid default for {key}: {err}"),
+    }
+}
+
+/// Parse a boolean, also accepting the spellings kernel parameters usually use
+fn parse_bool(s: &str) -> Result<bool, &'static str> {
+    match s {
+        "true" | "1" | "yes" | "on" => Ok(true),
+        "false" | "0" | "no" | "off" => Ok(false),
+        _ => Err("expected true or false"),
+    }
+}
+
+fn parse_string(s: &str) -> Result<String, Infallible> {
+    Ok(s.to_owned())
+}
+
+fn parse_path(s: &str) -> Result<PathBuf, &'static str> {
+    if s.is_empty() {
+        return Err("expected a path");
+    }
+    Ok(Path::new(s).to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reader(file: &str, cmdline: &str) -> Reader {
+        Reader {
+            path: PathBuf::from("demolition.toml"),
+            file: file.parse().unwrap(),
+            cmdline: parse_cmdline(cmdline),
+            overrides: HashMap::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    fn lookup(reader: &mut Reader, volume: Option<&str>, key: &str) -> Option<String> {
+        reader.lookup(volume, key).map(|(_, value)| value)
+    }
+
+    const VOLUMES: &str = "
+        keep-count = 1
+        [volumes.a]
+        keep-count = 2
+        [volumes.b]
+    ";
+
+    #[test]
+    fn volume_wins_in_the_same_place() {
+        let mut reader = reader(VOLUMES, "");
+        assert_eq!(lookup(&mut reader, Some("a"), "keep-count").unwrap(), "2");
+        assert_eq!(lookup(&mut reader, Some("b"), "keep-count").unwrap(), "1");
+    }
+
+    #[test]
+    fn kernel_parameter_wins_over_volume_in_file() {
+        let mut reader = reader(VOLUMES, "quiet demolition.keep-count=5");
+        assert_eq!(lookup(&mut reader, Some("a"), "keep-count").unwrap(), "5");
+        assert_eq!(lookup(&mut reader, Some("b"), "keep-count").unwrap(), "5");
+    }
+
+    #[test]
+    fn volume_kernel_parameter_wins() {
+        let mut reader = reader(VOLUMES, "demolition.keep-count=5 demolition.a.keep-count=7");
+        assert_eq!(lookup(&mut reader, Some("a"), "keep-count").unwrap(), "7");
+        assert_eq!(lookup(&mut reader, Some("b"), "keep-count").unwrap(), "5");
+    }
+
+    #[test]
+    fn only_shared_keys_apply_to_volumes() {
+        let mut reader = reader("template = 'blank'\n[volumes.a]", "");
+        assert_eq!(lookup(&mut reader, Some("a"), "template"), None);
+    }
+
+    #[test]
+    fn command_line_wins() {
+        let mut reader = reader("dry-run = false", "demolition.dry-run=false");
+        reader.overrides.insert("dry-run", "true".to_owned());
+        let (origin, value) = reader.lookup(None, "dry-run").unwrap();
+        assert_eq!(origin, "--dry-run on the command line");
+        assert_eq!(value, "true");
+    }
+
+    #[test]
+    fn kernel_parameter_without_value() {
+        let mut reader = reader("", "demolition.degraded");
+        assert_eq!(lookup(&mut reader, None, "degraded").unwrap(), "true");
+    }
+
+    #[test]
+    fn invalid_type() {
+        let mut reader = reader("keep-count = [1]", "");
+        assert_eq!(lookup(&mut reader, None, "keep-count"), None);
+        assert_eq!(reader.errors.len(), 1);
+    }
+
+    #[test]
+    fn volume_sets_required_keys() {
+        let mut reader = reader(
+            "[volumes.root]\nbackup-format = '%Y'\nkeep-count = 2\nkeep-duration = '1h'",
+            "",
+        );
+        let volumes = reader.volume_names().unwrap();
+        let volume = reader.volume(&volumes[0]);
+        assert_eq!(reader.errors, Vec::<String>::new());
+        assert_eq!(volume.backup_format, "%Y");
+        assert_eq!(volume.retention.last, 2);
+    }
+
+    #[test]
+    fn volume_without_required_key() {
+        let mut reader = reader("backup-format = '%Y'\nkeep-count = 1\n[volumes.a]", "");
+        reader.volume("a");
+        assert_eq!(
+            reader.errors,
+            [
+                "keep-duration is not set for volume a or for all volumes, in demolition.toml or \
+              DEMOLITION_A_KEEP_DURATION"
+            ]
+        );
+    }
+
+    #[test]
+    fn legacy_keys_with_volumes() {
+        let mut reader = reader("template = 'blank'\n[volumes.root]", "");
+        reader.volume_names();
+        assert_eq!(reader.errors.len(), 1);
+    }
+
+    #[test]
+    fn empty_volumes() {
+        let mut reader = reader("[volumes]", "");
+        assert_eq!(reader.volume_names(), Some(Vec::new()));
+        assert_eq!(reader.errors, ["no volumes configured in demolition.toml"]);
+    }
+
+    #[test]
+    fn priority() {
+        let reader = reader("path = '/mnt'", "demolition.device=LABEL=root");
+        assert!(reader.priority("device") > reader.priority("path"));
+        assert_eq!(reader.priority("fsid"), None);
+    }
+
+    #[test]
+    fn kernel_spellings_of_booleans() {
+        let mut reader = reader("", "demolition.dry-run=yes demolition.skip=1");
+        assert!(reader.get(None, "dry-run", "false", parse_bool));
+        assert!(reader.cmdline_flag("skip"));
+        assert_eq!(parse_bool("off"), Ok(false));
+    }
+
+    #[test]
+    fn invalid_flag_is_ignored() {
+        let reader = reader("", "demolition.skip=maybe");
+        assert!(!reader.cmdline_flag("skip"));
+        assert!(reader.errors.is_empty());
+    }
+
+    #[test]
+    fn split_cmdline_quotes() {
+        assert_eq!(
+            split_cmdline("  root=/dev/sda1 demolition.template=\"a b\"  quiet\n"),
+            ["root=/dev/sda1", "demolition.template=a b", "quiet"]
+        );
+    }
+
+    #[test]
+    fn parse_cmdline_prefix() {
+        let params = parse_cmdline("demolition.skip demolition.a.keep-count=3 demolitionx=1");
+        assert_eq!(params.len(), 2);
+        assert_eq!(params["skip"], None);
+        assert_eq!(params["a.keep-count"].as_deref(), Some("3"));
+    }
+
+    #[test]
+    fn env_var_names() {
+        assert_eq!(env_var(None, "keep-count"), "DEMOLITION_KEEP_COUNT");
+        assert_eq!(
+            env_var(Some("var-log.2"), "backup-dir"),
+            "DEMOLITION_VAR_LOG_2_BACKUP_DIR"
+        );
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::{fmt, fs, io};
+
+use crate::btrfs;
+use crate::superblock::{self, Superblock};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Which block device to mount, in the same forms fstab accepts
+#[derive(Debug, Clone)]
+pub enum Spec {
+    Path(PathBuf),
+    /// Filesystem UUID
+    Uuid(String),
+    /// Filesystem label
+    Label(String),
+    /// GPT partition UUID
+    PartUuid(String),
+    /// GPT partition name
+    PartLabel(String),
+}
+
+impl FromStr for Spec {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((tag, value)) = s.split_once('=') else {
+            return Ok(Self::Path(s.into()));
+        };
+        let spec: fn(String) -> Self = match tag {
+            "UUID" => |uuid| Self::Uuid(uuid.to_ascii_lowercase()),
+            "LABEL" => Self::Label,
+            "PARTUUID" => |uuid| Self::PartUuid(uuid.to_ascii_lowercase()),
+            "PARTLABEL" => Self::PartLabel,
+            // Paths can contain = too, such as the links in /dev/disk/by-partlabel
+            _ => return Ok(Self::Path(s.into())),
+        };
+
+        if value.is_empty() {
+            return Err("expected a value after =");
+        }
+        Ok(spec(value.to_owned()))
+    }
+}
+
+impl fmt::Display for Spec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Path(path) => write!(f, "{}", path.display()),
+            Self::Uuid(uuid) => write!(f, "UUID={uuid}"),
+            Self::Label(label) => write!(f, "LABEL={label}"),
+            Self::PartUuid(uuid) => write!(f, "PARTUUID={uuid}"),
+            Self::PartLabel(label) => write!(f, "PARTLABEL={label}"),
+        }
+    }
+}
+
+impl Spec {
+    /// Find the device, first through the `/dev/disk/by-*` symlinks, then by scanning every block
+    /// device in case udev hasn't created them
+    pub fn resolve(&self) -> Option<PathBuf> {
+        let (dir, value) = match self {
+            Self::Path(path) => return path.exists().then(|| path.clone()),
+            Self::Uuid(uuid) => ("by-uuid", uuid),
+            Self::Label(label) => ("by-label", label),
+            Self::PartUuid(uuid) => ("by-partuuid", uuid),
+            Self::PartLabel(label) => ("by-partlabel", label),
+        };
+
+        let link = Path::new("/dev/disk").join(dir).join(udev_escape(value));
+        if let Ok(path) = fs::canonicalize(&link) {
+            log::debug!("found {self} through '{}'", link.display());
+            return Some(path);
+        }
+
+        self.scan()
+    }
+
+    fn scan(&self) -> Option<PathBuf> {
+        let (_, device) = block_devices()
+            .into_iter()
+            .find(|(sys, device)| self.matches(sys, device))?;
+        log::debug!("found {self} by scanning: '{}'", device.display());
+        Some(device)
+    }
+
+    fn matches(&self, sys: &Path, device: &Path) -> bool {
+        match self {
+            Self::Path(_) => false,
+            Self::Uuid(uuid) => read_superblock(device)
+                .is_some_and(|superblock| superblock::format_uuid(&superblock.fsid) == *uuid),
+            Self::Label(label) => {
+                read_superblock(device).is_some_and(|superblock| superblock.label == *label)
+            }
+            // Only partitions have these, and not every kernel reports the UUID
+            Self::PartUuid(uuid) => {
+                uevent(sys, "PARTUUID").is_some_and(|v| v.eq_ignore_ascii_case(uuid))
+            }
+            Self::PartLabel(label) => uevent(sys, "PARTNAME").is_some_and(|v| v == *label),
+        }
+    }
+}
+
+/// Every block device, as its sysfs directory and its device node
+fn block_devices() -> Vec<(PathBuf, PathBuf)> {
+    let entries = match fs::read_dir("/sys/class/block") {
+        Ok(entries) => entries,
+        Err(err) => {
+            log::debug!("failed to list block devices: {err}");
+            return Vec::new();
+        }
+    };
+
+    entries
+        .filter_map(Result::ok)
+        .map(|entry| (entry.path(), Path::new("/dev").join(entry.file_name())))
+        .collect()
+}
+
+fn read_superblock(device: &Path) -> Option<Superblock> {
+    match superblock::read(device) {
+        Ok(Some(superblock)) if superblock.checksum == superblock::Checksum::Invalid => {
+            log::debug!("ignoring corrupt superblock on '{}'", device.display());
+            None
+        }
+        Ok(superblock) => superblock,
+        Err(err) => {
+            log::trace!("failed to read superblock of '{}': {err}", device.display());
+            None
+        }
+    }
+}
+
+/// Read a value from a block device's `uevent` file in sysfs
+fn uevent(sys: &Path, key: &str) -> Option<String> {
+    let uevent = fs::read_to_string(sys.join("uevent")).ok()?;
+    uevent.lines().find_map(|line| {
+        let (k, v) = line.split_once('=')?;
+        (k == key).then(|| v.to_owned())
+    })
+}
+
+/// Escape a value the way udev does for `/dev/disk/by-*` symlink names
+fn udev_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c.is_ascii_alphanumeric() || "#+-.:=@_".contains(c) || !c.is_ascii() {
+            escaped.push(c);
+        } else {
+            escaped.push_str(&format!("\\x{:02x}", c as u32));
+        }
+    }
+    escaped
+}
+
+/// Wait up to `timeout` for `device` to show up, returning where it is
+pub fn wait(device: &Spec, timeout: Duration) -> Option<PathBuf> {
+    poll(timeout, &format!("device {device}"), || device.resolve())
+}
+
+/// Register every member of the multi-device filesystem on `device` with the kernel, waiting up
+/// to `timeout` for all of them to show up
+///
+/// Returns whether all members were found.
+pub fn register_members(
+    device: &Path,
+    superblock: &Superblock,
+    timeout: Duration,
+) -> io::Result<bool> {
+    let what = format!("all {} devices of the filesystem", superblock.num_devices);
+    let mut registered = HashSet::new();
+    poll(timeout, &what, || {
+        for (_, member) in block_devices() {
+            if registered.contains(&member)
+                || read_superblock(&member).is_none_or(|other| other.fsid != superblock.fsid)
+            {
+                continue;
+            }
+
+            match btrfs::scan_device(&member) {
+                Ok(()) => {
+                    log::debug!("registered device '{}'", member.display());
+                    registered.insert(member);
+                }
+                Err(err) => log::warn!("failed to register device '{}': {err}", member.display()),
+            }
+        }
+
+        match btrfs::devices_ready(device) {
+            Ok(true) => Some(Ok(true)),
+            Ok(false) => None,
+            Err(err) => Some(Err(err)),
+        }
+    })
+    .unwrap_or(Ok(false))
+}
+
+/// Call `f` until it returns something, for up to `timeout`
+fn poll<T>(timeout: Duration, what: &str, mut f: impl FnMut() -> Option<T>) -> Option<T> {
+    let start = Instant::now();
+    let mut next_log = Duration::ZERO;
+    loop {
+        if let Some(found) = f() {
+            return Some(found);
+        }
+
+        let waited = start.elapsed();
+        if waited >= timeout {
+            return None;
+        }
+        if waited >= next_log {
+            if next_log.is_zero() {
+                log::info!("waiting for {what}");
+            } else {
+                log::info!(
+                    "still waiting for {what} ({}s of {}s)",
+                    waited.as_secs(),
+                    timeout.as_secs()
+                );
+            }
+            next_log += LOG_INTERVAL;
+        }
+
+        thread::sleep(POLL_INTERVAL.min(timeout - waited));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags() {
+        let spec: Spec = "UUID=ABCD-1234".parse().unwrap();
+        assert_eq!(spec.to_string(), "UUID=abcd-1234");
+        let spec: Spec = "PARTLABEL=root=a".parse().unwrap();
+        assert_eq!(spec.to_string(), "PARTLABEL=root=a");
+        assert!("LABEL=".parse::<Spec>().is_err());
+    }
+
+    #[test]
+    fn paths_with_equals() {
+        let spec: Spec = "/dev/disk/by-partlabel/a=b".parse().unwrap();
+        assert!(
+            matches!(spec, Spec::Path(path) if path == Path::new("/dev/disk/by-partlabel/a=b"))
+        );
+        let spec: Spec = "label=root".parse().unwrap();
+        assert!(matches!(spec, Spec::Path(_)));
+    }
+
+    #[test]
+    fn udev_escapes() {
+        assert_eq!(udev_escape("my disk/a=b"), "my\\x20disk\\x2fa=b");
+    }
+}
@@ -0,0 +1,95 @@
+//! Comparing two directory trees, such as a backup and the volume that replaced it.
+
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fs::{self, Metadata};
+use std::os::unix::fs::MetadataExt as _;
+use std::path::Path;
+use std::{fmt, io};
+
+/// A difference between the trees, shown with the same letters as `git diff --name-status`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Added,
+    Removed,
+    Modified,
+    /// Replaced by something of a different type, such as a directory by a file
+    Type,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Added => "A",
+            Self::Removed => "D",
+            Self::Modified => "M",
+            Self::Type => "T",
+        })
+    }
+}
+
+/// Compare the trees at `old` and `new`, calling `report` with each change and its path relative to
+/// them, in sorted order
+///
+/// Files are compared by metadata rather than contents: type, permissions, owner, size and mtime,
+/// and the targets of symlinks. Added and removed directories are reported once, without their
+/// contents.
+pub fn diff(old: &Path, new: &Path, report: &mut impl FnMut(Change, &Path)) -> io::Result<()> {
+    walk(old, new, Path::new(""), report)
+}
+
+fn walk(
+    old: &Path,
+    new: &Path,
+    dir: &Path,
+    report: &mut impl FnMut(Change, &Path),
+) -> io::Result<()> {
+    let old_names = names(&old.join(dir))?;
+    let new_names = names(&new.join(dir))?;
+
+    for name in old_names.union(&new_names) {
+        let path = dir.join(name);
+        if !new_names.contains(name) {
+            report(Change::Removed, &path);
+            continue;
+        }
+        if !old_names.contains(name) {
+            report(Change::Added, &path);
+            continue;
+        }
+
+        let (old_path, new_path) = (old.join(&path), new.join(&path));
+        let a = fs::symlink_metadata(&old_path)?;
+        let b = fs::symlink_metadata(&new_path)?;
+        if a.file_type() != b.file_type() {
+            report(Change::Type, &path);
+        } else if a.is_dir() {
+            // A directory's mtime changes with its entries, which are compared on their own
+            if !same_attributes(&a, &b) {
+                report(Change::Modified, &path);
+            }
+            walk(old, new, &path, report)?;
+        } else if a.is_symlink() {
+            if !same_attributes(&a, &b) || fs::read_link(&old_path)? != fs::read_link(&new_path)? {
+                report(Change::Modified, &path);
+            }
+        } else if !same_attributes(&a, &b)
+            || a.len() != b.len()
+            || (a.mtime(), a.mtime_nsec()) != (b.mtime(), b.mtime_nsec())
+        {
+            report(Change::Modified, &path);
+        }
+    }
+
+    Ok(())
+}
+
+fn names(dir: &Path) -> io::Result<BTreeSet<OsString>> {
+    dir.read_dir()?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect()
+}
+
+fn same_attributes(a: &Metadata, b: &Metadata) -> bool {
+    (a.mode(), a.uid(), a.gid()) == (b.mode(), b.uid(), b.gid())
+}
@@ -1,54 +1,37 @@
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::path::Path;
 use std::time::SystemTime;
 use std::{fs, io};
 
-use rustix::mount::{MountFlags, UnmountFlags, mount, unmount};
+use rustix::io::Errno;
+
+mod backups;
+mod btrfs;
+mod cli;
+mod commands;
+mod config;
+mod device;
+mod diff;
+mod mount;
+mod mountinfo;
+mod plan;
+mod retention;
+mod space;
+mod subvolume;
+mod superblock;
 
 const EXIT_ENV: i32 = 1;
 const EXIT_ERR: i32 = 2;
-
-#[macro_export]
-macro_rules! env {
-    (os, $e:literal, $dbg:literal) => {{
-        #[cfg(debug_assertions)]
-        let v = ::std::env::var_os($e).unwrap_or($dbg.into());
-        #[cfg(not(debug_assertions))]
-        let Some(v) = ::std::env::var_os($e) else {
-            $crate::env!(bail, "env var is not set: ", $e);
-        };
-        v
-    }};
-    (str, $e:literal, $dbg:literal) => {
-        match $crate::env!(os, $e, $dbg).into_string() {
-            Ok(v) => v,
-            Err(_) => $crate::env!(bail, "env var is not unicode: ", $e),
-        }
-    };
-    (from_str($t:ty), $e:literal, $dbg:literal) => {
-        match $crate::env!(str, $e, $dbg).parse::<$t>() {
-            Ok(v) => v,
-            Err(err) => $crate::env!(bail, "invalid format for ", $e, ": {}"; err),
-        }
-    };
-    (parse($parse:path), $e:literal, $dbg:literal) => {
-        match $parse($crate::env!(str, $e, $dbg).as_str()) {
-            Ok(v) => v,
-            Err(err) => $crate::env!(bail, "invalid format for ", $e, ": {}"; err),
-        }
-    };
-    (bail, $($err:literal),+ $(; $($rest:tt)*)?) => {{
-        ::log::error!(::std::concat!($($err),+) $(, $($rest)*)?);
-        ::std::process::exit($crate::EXIT_ENV);
-    }};
-}
+const EXIT_NO_DEVICE: i32 = 3;
+const EXIT_PROBLEMS: i32 = 4;
 
 #[macro_export]
 macro_rules! bail {
-    ($($t:tt)+) => {{
-        ::log::error!($($t)+);
-        ::std::process::exit($crate::EXIT_ERR);
-    }};
+    ($($t:tt)+) => {
+        return Err($crate::Error {
+            message: format!($($t)+),
+            code: $crate::EXIT_ERR,
+        })
+    };
 }
 
 #[macro_export]
@@ -61,170 +44,538 @@ macro_rules! unwrap {
     };
 }
 
-macro_rules! log_dry {
-    ($msg:literal $($rest:tt)*) => {
-        ::log::info!(::std::concat!("dry run: ", $msg) $($rest)*)
-    };
+/// A fatal error, which is logged once everything has been cleaned up
+#[derive(Debug)]
+struct Error {
+    message: String,
+    code: i32,
 }
 
 fn main() {
     env_logger::Builder::from_env("DEMOLITION_LOG").init();
 
-    let device = &PathBuf::from(env!(os, "DEMOLITION_DEVICE", "/dev/mapper/crypted"));
-    let mount_dir = &PathBuf::from(env!(os, "DEMOLITION_MOUNT_DIR", "./mnt"));
-    let root_volume = mount_dir.join(env!(os, "DEMOLITION_ROOT_VOLUME", "root"));
-    let backups_dir = mount_dir.join(env!(os, "DEMOLITION_BACKUP_DIR", "root-backups"));
-    let backup_format = env!(str, "DEMOLITION_BACKUP_FORMAT", "%Y%m%d_%H%M%S");
-    let keep_during = env!(
-        parse(humantime::parse_duration),
-        "DEMOLITION_KEEP_DURATION",
-        "1day"
-    );
-    let keep_count = env!(from_str(u16), "DEMOLITION_KEEP_COUNT", "1");
-    let dry_run = cfg!(debug_assertions);
+    let args = match cli::parse() {
+        Ok(args) => args,
+        Err(err) => {
+            log::error!("{err}, see --help");
+            std::process::exit(EXIT_ENV);
+        }
+    };
 
-    log::trace!("mkdir '{}'", mount_dir.display());
-    match fs::create_dir(mount_dir) {
-        Ok(()) => log::debug!("created mount point"),
-        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
-            log::warn!("mount point already exists");
+    let config = config::Config::load(&args);
+    let result = match args.command {
+        cli::Command::Run => run(&config),
+        cli::Command::List { volume } => commands::list(&config, volume.as_deref()),
+        cli::Command::Prune { volume } => commands::prune(&config, volume.as_deref()),
+        cli::Command::Restore { volume, backup } => commands::restore(&config, &volume, &backup),
+        cli::Command::Diff { volume, old, new } => {
+            commands::diff(&config, &volume, old.as_deref(), new.as_deref())
         }
-        Err(err) => bail!("failed to create mount point: {err}"),
+        cli::Command::Status => commands::status(&config),
+        cli::Command::Check => commands::check(&config),
+    };
+    if let Err(err) = result {
+        log::error!("{}", err.message);
+        std::process::exit(err.code);
     }
+}
 
-    let flags = MountFlags::NOATIME | MountFlags::NODEV | MountFlags::NOEXEC | MountFlags::NOSUID;
-    log::trace!(
-        "mount -t btrfs '{}' '{}'",
-        device.display(),
-        mount_dir.display()
-    );
-    if let Err(err) = mount(device, mount_dir, "btrfs", flags, None) {
-        bail!("mount failed: {err}");
+fn run(config: &config::Config) -> Result<(), Error> {
+    if config.skip {
+        log::info!("skipping this boot, as asked on the kernel command line");
+        return Ok(());
+    }
+
+    let (mount, fsid) = open(config)?;
+    let mount_dir = mount.path();
+
+    // Check every volume before touching any of them, so a bad volume doesn't leave the others
+    // half wiped
+    let kinds = config
+        .volumes
+        .iter()
+        .map(|volume| check_volume(mount_dir, volume, &fsid))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let now = SystemTime::now().into();
+    let mut volumes = Vec::new();
+    for (volume, kind) in config.volumes.iter().zip(kinds) {
+        let back_up = plan::back_up(mount_dir, volume, kind)?;
+        volumes.push(plan::Volume {
+            name: volume.name.clone(),
+            backups: plan::prune(mount_dir, volume, &fsid, now, back_up.as_ref())?,
+            back_up,
+            create: Some(plan::Create {
+                path: mount_dir.join(&volume.subvolume),
+                template: volume.template.as_ref().map(|t| mount_dir.join(t)),
+            }),
+        });
+    }
+
+    let plan = plan::Plan {
+        root: mount_dir.to_owned(),
+        free_space: plan::free_space(mount_dir, &volumes, config),
+        volumes,
     };
+    finish(config, mount, &plan)
+}
 
-    match root_volume.metadata().and_then(|m| m.created()) {
-        Ok(created) => {
-            let created = chrono::DateTime::from(created);
-            let created = created.format(&backup_format).to_string();
-            let backup = backups_dir.join(created);
-
-            log::trace!("mv '{}' '{}'", root_volume.display(), backup.display());
-            if dry_run {
-                log_dry!("mv '{}' '{}'", root_volume.display(), backup.display());
-            } else if let Err(err) = fs::rename(&root_volume, backup) {
-                bail!("failed to move existing root volume into backups: {err}");
-            }
+/// Show `plan` for a dry run, or else carry it out, then unmount
+fn finish(config: &config::Config, mount: mount::Mount, plan: &plan::Plan) -> Result<(), Error> {
+    if config.dry_run {
+        if config.json {
+            println!("{:#}", plan.to_json());
+        } else {
+            print!("{plan}");
         }
-        Err(err) if err.kind() == io::ErrorKind::NotFound => {
-            log::debug!("no old root volume found");
+    } else {
+        execute(mount.path(), plan)?;
+    }
+
+    unwrap!(mount.unmount(), "umount failed: {err}");
+    Ok(())
+}
+
+/// Carry out `plan`, stopping at the first step that fails
+fn execute(mount_dir: &Path, plan: &plan::Plan) -> Result<(), Error> {
+    let mut kept = Vec::new();
+    for volume in &plan.volumes {
+        // The fresh root volume is created straight away, so failing on a later volume can't leave
+        // this one without any
+        if let Some(back_up) = &volume.back_up {
+            back_up_root(&volume.name, back_up)?;
+        }
+        if let Some(create) = &volume.create {
+            create_root(&create.path, create.template.as_deref())?;
         }
-        Err(err) => bail!("failed to get root volume creation date: {err}"),
+        kept.push(remove_backups(&volume.name, &volume.backups));
     }
 
-    let mut backups = Vec::new();
-    let entries = unwrap!(
-        backups_dir.read_dir(),
-        "failed to get entries of backups directory: {err}"
+    if let Some(free) = &plan.free_space {
+        free_space(mount_dir, kept, free.min_free, free.min_keep);
+    }
+    Ok(())
+}
+
+/// Get to the top-level subvolume, mounting it if needed, and find out which filesystem it is
+fn open(config: &config::Config) -> Result<(mount::Mount, [u8; 16]), Error> {
+    let mount = match &config.target {
+        config::Target::Device(device) => mount_device(config, device)?,
+        config::Target::Path(path) => {
+            match rustix::fs::statfs(path) {
+                Ok(stat) if stat.f_type as u32 == btrfs::SUPER_MAGIC => {}
+                Ok(_) => bail!("not on a btrfs filesystem: '{}'", path.display()),
+                Err(err) => bail!("failed to check '{}': {err}", path.display()),
+            }
+            log::debug!("using '{}' without mounting", path.display());
+            mount::Mount::existing(path)
+        }
+    };
+
+    let fsid = unwrap!(
+        fs::File::open(mount.path()).and_then(btrfs::fsid),
+        "failed to get filesystem id: {err}"
     );
-    for entry in entries {
-        let entry = match entry {
-            Ok(ok) => ok,
-            Err(err) => {
-                log::warn!("skipping backup: {err}");
-                continue;
+    Ok((mount, fsid))
+}
+
+/// Find and check the device, then reuse an existing mount of it or mount it
+fn mount_device(config: &config::Config, spec: &device::Spec) -> Result<mount::Mount, Error> {
+    let Some(device) = &device::wait(spec, config.device_timeout) else {
+        return Err(Error {
+            message: format!("device not found: {spec}"),
+            code: EXIT_NO_DEVICE,
+        });
+    };
+    log::debug!("using device '{}'", device.display());
+    let superblock = check_superblock(device, config.fsid)?;
+
+    let mut mount_options = config.mount_options.clone();
+    if superblock.num_devices > 1 {
+        log::debug!(
+            "filesystem has {} devices, this is device {}",
+            superblock.num_devices,
+            superblock.devid
+        );
+        let found = unwrap!(
+            device::register_members(device, &superblock, config.member_timeout),
+            "failed to register filesystem devices: {err}"
+        );
+        if !found {
+            if !config.degraded {
+                bail!(
+                    "not all {} devices of the filesystem were found",
+                    superblock.num_devices
+                );
             }
-        };
+            log::warn!("not all devices of the filesystem were found, mounting degraded");
+            mount_options.add("degraded");
+        }
+    }
 
-        let modified = unwrap!(
-            entry.metadata().and_then(|m| m.modified()),
-            "failed to get backup creation date: {err}"
+    if config.private_namespace {
+        log::debug!("unsharing mount namespace");
+        unwrap!(
+            mount::unshare_namespace(),
+            "failed to create private mount namespace: {err}"
         );
-        let age = modified
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap_or_default();
-        backups.push((age, entry));
-    }
-    backups.sort_unstable_by_key(|(age, _)| *age);
-
-    let remove_count = backups.len().saturating_sub(keep_count.into());
-    log::trace!("removing up to {remove_count} of {} backups", backups.len());
-    let remove = backups
-        .into_iter()
-        .take(remove_count)
-        .take_while(|(age, _)| *age > keep_during);
-    for (_, backup) in remove {
-        log::trace!("removing backup: {}", backup.path().display());
-
-        if dry_run {
-            log_dry!(
-                "btrfs subvolume delete --recursive '{}'",
-                backup.path().display()
+    }
+
+    match mount::Mount::find_existing(&superblock.fsid) {
+        Ok(Some(mount)) => {
+            log::info!(
+                "filesystem is already mounted, using '{}'",
+                mount.path().display()
             );
-            continue;
-        }
-
-        let mut cmd = Command::new("btrfs");
-        cmd.args(["subvolume", "delete", "--recursive"]);
-        cmd.arg(backup.path());
-        cmd.stdin(Stdio::null());
-        match cmd.status() {
-            Ok(status) if status.success() => {}
-            Ok(status) => {
-                if let Some(code) = status.code() {
-                    log::warn!(
-                        "btrfs subvolume delete '{}' exitted with {code}",
-                        backup.path().display()
-                    )
-                } else {
-                    log::warn!(
-                        "btrfs subvolume delete '{}' exitted with unknown exit code",
-                        backup.path().display()
-                    )
-                }
+            return Ok(mount);
+        }
+        Ok(None) => {}
+        Err(err) => log::warn!("failed to look for an existing mount: {err}"),
+    }
+
+    mount(device, &config.mount_dir, &mount_options)
+}
+
+/// Mount the top-level subvolume, detached if the kernel supports it
+fn mount(device: &Path, mount_dir: &Path, options: &mount::Options) -> Result<mount::Mount, Error> {
+    // Everything goes through /proc/self/fd, so a detached mount needs /proc
+    if Path::new("/proc/self/fd").is_dir() {
+        log::trace!("fsmount -t btrfs -o {options} '{}'", device.display());
+        match mount::Mount::detached(device, options) {
+            Ok(mount) => return Ok(mount),
+            Err(err) if err.raw_os_error() == Some(Errno::NOSYS.raw_os_error()) => {
+                log::debug!("kernel lacks the new mount API, mounting on a directory");
             }
-            Err(err) => {
-                log::warn!(
-                    "failed to get btrfs exit code while removing backup '{}': {err}",
-                    backup.path().display()
-                );
+            Err(err) => bail!("mount failed: {err}"),
+        }
+    } else {
+        log::debug!("/proc is not mounted, mounting on a directory");
+    }
+
+    Ok(unwrap!(
+        mount::Mount::on_dir(device, mount_dir, options),
+        "mount failed: {err}"
+    ))
+}
+
+/// Refuse to touch anything that isn't the expected btrfs filesystem
+fn check_superblock(
+    device: &Path,
+    fsid: Option<[u8; 16]>,
+) -> Result<superblock::Superblock, Error> {
+    let superblock = match superblock::read(device) {
+        Ok(Some(superblock)) => superblock,
+        Ok(None) => bail!("no btrfs filesystem found on '{}'", device.display()),
+        Err(err) => bail!("failed to read superblock of '{}': {err}", device.display()),
+    };
+
+    match superblock.checksum {
+        superblock::Checksum::Valid => {}
+        superblock::Checksum::Invalid => {
+            bail!("superblock checksum mismatch on '{}'", device.display())
+        }
+        checksum @ superblock::Checksum::Unsupported(_) => {
+            log::warn!("cannot verify {checksum} superblock checksum, skipping check");
+        }
+    }
+
+    if let Some(fsid) = fsid
+        && superblock.fsid != fsid
+    {
+        bail!(
+            "filesystem on '{}' is {}, expected {}",
+            device.display(),
+            superblock::format_uuid(&superblock.fsid),
+            superblock::format_uuid(&fsid)
+        );
+    }
+
+    Ok(superblock)
+}
+
+/// Check that `volume` can be wiped, returning what is currently there
+fn check_volume(
+    mount_dir: &Path,
+    volume: &config::Volume,
+    fsid: &[u8; 16],
+) -> Result<Option<subvolume::Kind>, Error> {
+    let name = &volume.name;
+
+    // Check the template before moving the old root away, so a missing template doesn't leave the
+    // system without a root volume
+    if let Some(template) = &volume.template {
+        let template = mount_dir.join(template);
+        match subvolume::kind(&template, fsid) {
+            Ok(subvolume::Kind::Subvolume) => {}
+            Ok(kind) => bail!("template for {name} is {kind}: '{}'", template.display()),
+            Err(err) => bail!(
+                "failed to find template for {name} '{}': {err}",
+                template.display()
+            ),
+        }
+    }
+
+    Ok(
+        match subvolume::kind(&mount_dir.join(&volume.subvolume), fsid) {
+            Ok(subvolume::Kind::PlainDir) if volume.plain_dirs == subvolume::PlainDirs::Remove => {
+                log::warn!("volume {name} is a plain directory, moving it into backups");
+                Some(subvolume::Kind::PlainDir)
+            }
+            Ok(subvolume::Kind::PlainDir) => bail!(
+                "volume {name} is a plain directory, refusing to touch it without plain-dirs = \"remove\" (DEMOLITION_PLAIN_DIRS=remove)"
+            ),
+            Ok(kind @ subvolume::Kind::Subvolume) => Some(kind),
+            Ok(kind) => bail!("volume {name} is {kind}, refusing to touch it"),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => bail!("failed to check volume {name}: {err}"),
+        },
+    )
+}
+
+/// Move the old root volume into its backups
+fn back_up_root(name: &str, back_up: &plan::BackUp) -> Result<(), Error> {
+    match back_up.mode {
+        backups::Mode::Rename => {
+            log::trace!("mv '{}' '{}'", back_up.from.display(), back_up.to.display());
+            if let Err(err) = fs::rename(&back_up.from, &back_up.to) {
+                bail!("failed to move existing volume {name} into backups: {err}");
+            }
+        }
+        backups::Mode::Snapshot => snapshot_root(&back_up.from, &back_up.to)?,
+    }
+    Ok(())
+}
+
+/// The backups of `volume`, newest first
+fn list_backups(
+    mount_dir: &Path,
+    volume: &config::Volume,
+    fsid: &[u8; 16],
+) -> Result<Vec<backups::Backup>, Error> {
+    let name = &volume.name;
+    let backups_dir = mount_dir.join(&volume.backup_dir);
+    Ok(unwrap!(
+        backups::list(&backups_dir, &volume.backup_format, fsid, volume.plain_dirs),
+        "failed to get entries of backups directory for {name}: {err}"
+    ))
+}
+
+/// Remove the backups that aren't kept, returning the ones kept by the retention policy
+///
+/// Pinned backups are left out, so they can never be removed to free space.
+fn remove_backups<'a>(name: &str, decisions: &'a [plan::Decision]) -> Vec<&'a backups::Backup> {
+    log::trace!(
+        "removing {} of {} backups of {name}",
+        decisions.iter().filter(|d| d.keep.is_none()).count(),
+        decisions.len()
+    );
+
+    let mut kept = Vec::new();
+    for decision in decisions {
+        let backup = &decision.backup;
+        match decision.keep {
+            Some(keep @ plan::Keep::Pinned(_)) => {
+                log::info!("keeping backup ({keep}): {}", backup.path.display());
+            }
+            Some(keep @ plan::Keep::Policy(_)) => {
+                log::debug!("keeping backup ({keep}): {}", backup.path.display());
+                kept.push(backup);
+            }
+            None => {
+                remove_backup(backup);
             }
         }
     }
+    kept
+}
 
-    log::trace!("creating new root volume: '{}'", root_volume.display());
-    if dry_run {
-        log_dry!(
-            "btrfs subvolume create --parents '{}'",
+/// Back up the root volume as a read-only snapshot, then delete it
+fn snapshot_root(root_volume: &Path, backup: &Path) -> Result<(), Error> {
+    log::trace!("snapshotting root volume read-only: '{}'", backup.display());
+    let tree = unwrap!(
+        subvolume::Tree::scan(root_volume),
+        "failed to find subvolumes in root volume: {err}"
+    );
+    unwrap!(
+        tree.check(),
+        "cannot delete old root volume, so not snapshotting it: {err}"
+    );
+
+    unwrap!(
+        btrfs::snapshot(root_volume, backup, true),
+        "failed to snapshot root volume into backups: {err}"
+    );
+    if let Err(err) = tree.delete() {
+        // Left behind, the snapshot would take the name of the next attempt's backup, since that
+        // is named after the same old root volume
+        log::trace!("removing snapshot again: '{}'", backup.display());
+        if let Err(err) = btrfs::destroy_subvolume(backup) {
+            log::error!("failed to remove snapshot '{}': {err}", backup.display());
+        }
+        bail!("failed to delete old root volume: {err}");
+    }
+    Ok(())
+}
+
+/// Create a fresh root volume, either empty or as a writable snapshot of `template`
+fn create_root(root_volume: &Path, template: Option<&Path>) -> Result<(), Error> {
+    match template {
+        Some(template) => log::trace!(
+            "snapshotting template '{}' as new root volume: '{}'",
+            template.display(),
             root_volume.display()
+        ),
+        None => log::trace!("creating new root volume: '{}'", root_volume.display()),
+    }
+
+    if let Some(parent) = root_volume.parent() {
+        unwrap!(
+            fs::create_dir_all(parent),
+            "failed to create parent of root volume: {err}"
         );
+    }
+    match template {
+        Some(template) => unwrap!(
+            btrfs::snapshot(template, root_volume, false),
+            "failed to snapshot template as root volume: {err}"
+        ),
+        None => unwrap!(
+            btrfs::create_subvolume(root_volume),
+            "failed to create empty root volume: {err}"
+        ),
+    }
+    Ok(())
+}
+
+/// Remove a backup, returning whether it was removed
+fn remove_backup(backup: &backups::Backup) -> bool {
+    if backup.source == backups::Source::Name {
+        log::trace!("removing backup: {}", backup.path.display());
     } else {
-        let mut cmd = Command::new("btrfs");
-        cmd.args(["subvolume", "create", "--parents"]);
-        cmd.arg(&root_volume);
-        cmd.stdin(Stdio::null());
-        match cmd.status() {
-            Ok(status) if status.success() => {}
-            Ok(status) => {
-                if let Some(code) = status.code() {
-                    log::warn!(
-                        "btrfs subvolume create --parents '{}' exitted with {code}",
-                        root_volume.display()
-                    )
-                } else {
-                    log::warn!(
-                        "btrfs subvolume create --parents '{}' exitted with unknown exit code",
-                        root_volume.display()
-                    )
-                }
-            }
+        log::warn!(
+            "removing backup dated by its {}: {}",
+            backup.source,
+            backup.path.display()
+        );
+    }
+
+    if backup.kind == subvolume::Kind::PlainDir {
+        return remove_plain_dir(backup);
+    }
+
+    let tree = match subvolume::Tree::scan(&backup.path) {
+        Ok(tree) => tree,
+        Err(err) => {
+            log::warn!(
+                "failed to find subvolumes in backup '{}': {err}",
+                backup.path.display()
+            );
+            return false;
+        }
+    };
+
+    let removed = match tree.delete() {
+        Ok(_) => true,
+        Err(err) => {
+            log::warn!("failed to delete backup '{}': {err}", backup.path.display());
+            false
+        }
+    };
+
+    if removed {
+        remove_pin(backup);
+    }
+    removed
+}
+
+/// Remove a backup that is a plain directory, returning whether it was removed
+fn remove_plain_dir(backup: &backups::Backup) -> bool {
+    match fs::remove_dir_all(&backup.path) {
+        Ok(()) => {
+            log::info!("removed plain directory: '{}'", backup.path.display());
+            remove_pin(backup);
+            true
+        }
+        Err(err) => {
+            log::warn!(
+                "failed to remove plain directory '{}': {err}",
+                backup.path.display()
+            );
+            false
+        }
+    }
+}
+
+fn remove_pin(backup: &backups::Backup) {
+    if let Some(pin) = backup.pin {
+        log::debug!("removing expired pin ({pin}): {}", backup.path.display());
+        if let Err(err) = fs::remove_file(backup.pin_path()) {
+            log::warn!("failed to remove expired pin marker: {err}");
+        }
+    }
+}
+
+/// Remove the oldest backups across all volumes until `min_free` is met, keeping at least
+/// `min_keep` of each volume's backups
+///
+/// Each volume's backups must be sorted newest first.
+fn free_space(
+    mount_dir: &Path,
+    mut volumes: Vec<Vec<&backups::Backup>>,
+    min_free: space::MinFree,
+    min_keep: u16,
+) {
+    loop {
+        let space = match space::space(mount_dir) {
+            Ok(space) => space,
             Err(err) => {
-                log::warn!("failed to get btrfs exit code while creating empty root volume {err}");
+                log::error!("failed to get free space of filesystem: {err}");
+                return;
             }
+        };
+        if min_free.is_met(space) {
+            log::debug!(
+                "{} of {} bytes available, minimum is {min_free}",
+                space.available,
+                space.total
+            );
+            return;
         }
-    }
 
-    log::trace!("umount '{}'", mount_dir.display());
-    if let Err(err) = unmount(mount_dir, UnmountFlags::empty()) {
-        bail!("umount failed: {err}");
+        let Some(oldest) = take_oldest(&mut volumes, min_keep) else {
+            log::warn!(
+                "only {} bytes available, but every volume is already down to {min_keep} backups",
+                space.available
+            );
+            return;
+        };
+        log::info!(
+            "only {} bytes available, minimum is {min_free}: removing oldest backup",
+            space.available
+        );
+        if !remove_backup(oldest) {
+            return;
+        }
+
+        // Subvolumes are cleaned up in the background, so their space isn't available until the
+        // cleaner catches up
+        log::trace!("waiting for subvolume cleaner");
+        if let Err(err) = fs::File::open(mount_dir).and_then(btrfs::wait_for_cleaner) {
+            log::warn!("failed to wait for deleted backups to be cleaned up: {err}");
+        }
     }
 }
+
+/// Take the oldest backup of any volume that has more than `min_keep` left
+///
+/// Each volume's backups must be sorted newest first.
+fn take_oldest<'a>(
+    volumes: &mut [Vec<&'a backups::Backup>],
+    min_keep: u16,
+) -> Option<&'a backups::Backup> {
+    volumes
+        .iter_mut()
+        .filter(|backups| backups.len() > min_keep.into())
+        .min_by_key(|backups| backups.last().map(|backup| backup.created))
+        .and_then(Vec::pop)
+}
@@ -0,0 +1,396 @@
+use std::ffi::CString;
+use std::fs::File;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{fmt, fs, io};
+
+use rustix::io::Errno;
+use rustix::mount::{
+    FsMountFlags, FsOpenFlags, MountAttrFlags, MountFlags, MountPropagationFlags, UnmountFlags,
+    fsconfig_create, fsconfig_set_flag, fsconfig_set_string, fsmount, fsopen, mount, mount_change,
+    unmount,
+};
+use rustix::thread::UnshareFlags;
+
+use crate::{btrfs, mountinfo};
+
+/// Generic mount options, and whether each one sets or clears its flag
+const FLAGS: &[(&str, MountFlags, bool)] = &[
+    ("ro", MountFlags::RDONLY, true),
+    ("rw", MountFlags::RDONLY, false),
+    ("nosuid", MountFlags::NOSUID, true),
+    ("suid", MountFlags::NOSUID, false),
+    ("nodev", MountFlags::NODEV, true),
+    ("dev", MountFlags::NODEV, false),
+    ("noexec", MountFlags::NOEXEC, true),
+    ("exec", MountFlags::NOEXEC, false),
+    ("sync", MountFlags::SYNCHRONOUS, true),
+    ("async", MountFlags::SYNCHRONOUS, false),
+    ("dirsync", MountFlags::DIRSYNC, true),
+    ("noatime", MountFlags::NOATIME, true),
+    ("atime", MountFlags::NOATIME, false),
+    ("nodiratime", MountFlags::NODIRATIME, true),
+    ("diratime", MountFlags::NODIRATIME, false),
+    ("relatime", MountFlags::RELATIME, true),
+    ("norelatime", MountFlags::RELATIME, false),
+    ("strictatime", MountFlags::STRICTATIME, true),
+    ("lazytime", MountFlags::LAZYTIME, true),
+    ("nolazytime", MountFlags::LAZYTIME, false),
+    ("nosymfollow", MountFlags::NOSYMFOLLOW, true),
+];
+
+/// Flags that apply to the mount rather than the filesystem, for the new mount API
+const ATTRS: &[(MountFlags, MountAttrFlags)] = &[
+    (MountFlags::RDONLY, MountAttrFlags::MOUNT_ATTR_RDONLY),
+    (MountFlags::NOSUID, MountAttrFlags::MOUNT_ATTR_NOSUID),
+    (MountFlags::NODEV, MountAttrFlags::MOUNT_ATTR_NODEV),
+    (MountFlags::NOEXEC, MountAttrFlags::MOUNT_ATTR_NOEXEC),
+    (MountFlags::NOATIME, MountAttrFlags::MOUNT_ATTR_NOATIME),
+    (
+        MountFlags::STRICTATIME,
+        MountAttrFlags::MOUNT_ATTR_STRICTATIME,
+    ),
+    (
+        MountFlags::NODIRATIME,
+        MountAttrFlags::MOUNT_ATTR_NODIRATIME,
+    ),
+    (
+        MountFlags::NOSYMFOLLOW,
+        MountAttrFlags::MOUNT_ATTR_NOSYMFOLLOW,
+    ),
+];
+
+/// Flags that apply to the filesystem, and their names for `fsconfig`
+const SUPERBLOCK_FLAGS: &[(MountFlags, &str)] = &[
+    (MountFlags::RDONLY, "ro"),
+    (MountFlags::SYNCHRONOUS, "sync"),
+    (MountFlags::DIRSYNC, "dirsync"),
+    (MountFlags::LAZYTIME, "lazytime"),
+];
+
+/// Options only used by fstab and mount(8), which the kernel doesn't understand
+const FSTAB_ONLY: &[&str] = &[
+    "defaults", "auto", "noauto", "nofail", "user", "nouser", "users", "owner", "group", "_netdev",
+];
+
+/// Prefixes of options only used by fstab and userspace tools, such as `x-systemd.*`
+const FSTAB_ONLY_PREFIXES: &[&str] = &["x-", "X-", "comment="];
+
+/// The top-level subvolume, which contains every other subvolume
+const TOP_LEVEL: &str = "subvolid=5";
+
+/// Mount options as written in fstab, split into generic flags and options for btrfs
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub flags: MountFlags,
+    /// Options for btrfs, starting with the one that selects the top-level subvolume
+    data: Vec<String>,
+}
+
+impl Options {
+    /// Add a btrfs option, unless it is already set
+    pub fn add(&mut self, option: &str) {
+        if !self.data.iter().any(|o| o == option) {
+            self.data.push(option.to_owned());
+        }
+    }
+
+    /// The btrfs options in the form `mount(2)` takes
+    pub fn data(&self) -> CString {
+        CString::new(self.data.join(",")).expect("options were checked for NUL bytes")
+    }
+}
+
+impl FromStr for Options {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.contains('\0') {
+            return Err("options can't contain NUL bytes");
+        }
+
+        let mut options = Self {
+            flags: MountFlags::empty(),
+            data: Vec::new(),
+        };
+        for option in s.split(',').map(str::trim) {
+            if option.is_empty()
+                || FSTAB_ONLY.contains(&option)
+                || FSTAB_ONLY_PREFIXES.iter().any(|p| option.starts_with(p))
+            {
+                continue;
+            }
+            // Every run writes to the filesystem
+            if option == "ro" {
+                return Err("the filesystem can't be mounted read-only");
+            }
+
+            if let Some(&(_, flag, set)) = FLAGS.iter().find(|(name, _, _)| *name == option) {
+                options.flags.set(flag, set);
+                continue;
+            }
+
+            if let Some(subvolume) = option.strip_prefix("subvolid=") {
+                if subvolume.trim_start_matches('0') != "5" {
+                    return Err("only the top-level subvolume can be mounted, with subvolid=5");
+                }
+                continue;
+            }
+            if let Some(subvolume) = option.strip_prefix("subvol=") {
+                if !subvolume.trim_matches('/').is_empty() {
+                    return Err("only the top-level subvolume can be mounted, with subvol=/");
+                }
+                continue;
+            }
+
+            options.add(option);
+        }
+
+        options.data.insert(0, TOP_LEVEL.to_owned());
+        Ok(options)
+    }
+}
+
+impl fmt::Display for Options {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags = FLAGS
+            .iter()
+            .filter(|(_, flag, set)| *set && self.flags.contains(*flag))
+            .map(|(name, _, _)| *name);
+        let mut options = flags.chain(self.data.iter().map(String::as_str));
+
+        if let Some(first) = options.next() {
+            f.write_str(first)?;
+        }
+        for option in options {
+            write!(f, ",{option}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Move into a new mount namespace, so nothing mounted from here on is visible to the rest of the
+/// system, and it is all torn down when the process exits
+pub fn unshare_namespace() -> io::Result<()> {
+    // SAFETY: only the mount namespace is unshared, not the fd table
+    unsafe { rustix::thread::unshare_unsafe(UnshareFlags::NEWNS)? };
+    // The new namespace starts with copies of the old mounts, which would still propagate new
+    // mounts back to their peers
+    mount_change(
+        "/",
+        MountPropagationFlags::PRIVATE | MountPropagationFlags::REC,
+    )?;
+    Ok(())
+}
+
+/// The mounted filesystem, which is unmounted again when this is dropped
+#[derive(Debug)]
+pub struct Mount {
+    path: PathBuf,
+    kind: Kind,
+}
+
+#[derive(Debug)]
+enum Kind {
+    /// Not attached anywhere, and reached through `/proc/self/fd`. The mount goes away when the
+    /// fd is closed.
+    Detached { _fd: OwnedFd },
+    Dir {
+        /// Whether to remove the mount point after unmounting
+        created: bool,
+    },
+    /// Mounted by someone else, so left alone
+    Existing,
+    /// Already cleaned up
+    Unmounted,
+}
+
+impl Mount {
+    /// Mount `device` without attaching it anywhere
+    ///
+    /// Fails with `ENOSYS` on kernels before 5.2, which lack the new mount API.
+    pub fn detached(device: &Path, options: &Options) -> io::Result<Self> {
+        let fs = fsopen("btrfs", FsOpenFlags::FSOPEN_CLOEXEC)?;
+        fsconfig_set_string(&fs, "source", device)?;
+        for option in &options.data {
+            match option.split_once('=') {
+                Some((key, value)) => fsconfig_set_string(&fs, key, value)?,
+                None => fsconfig_set_flag(&fs, option.as_str())?,
+            }
+        }
+        for &(flag, name) in SUPERBLOCK_FLAGS {
+            if options.flags.contains(flag) {
+                fsconfig_set_flag(&fs, name)?;
+            }
+        }
+        fsconfig_create(&fs)?;
+
+        let attrs = ATTRS
+            .iter()
+            .filter(|(flag, _)| options.flags.contains(*flag))
+            .fold(MountAttrFlags::empty(), |attrs, &(_, attr)| attrs | attr);
+        let fd = fsmount(&fs, FsMountFlags::FSMOUNT_CLOEXEC, attrs)?;
+
+        Ok(Self {
+            path: PathBuf::from(format!("/proc/self/fd/{}", fd.as_raw_fd())),
+            kind: Kind::Detached { _fd: fd },
+        })
+    }
+
+    /// Mount `device` on `dir` the classic way, creating `dir` if needed
+    pub fn on_dir(device: &Path, dir: &Path, options: &Options) -> io::Result<Self> {
+        log::trace!("mkdir '{}'", dir.display());
+        let created = match fs::create_dir(dir) {
+            Ok(()) => {
+                log::debug!("created mount point");
+                true
+            }
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                log::warn!("mount point already exists");
+                false
+            }
+            Err(err) => {
+                return Err(io::Error::new(
+                    err.kind(),
+                    format!("failed to create mount point: {err}"),
+                ));
+            }
+        };
+
+        log::trace!(
+            "mount -t btrfs -o {options} '{}' '{}'",
+            device.display(),
+            dir.display()
+        );
+        if let Err(err) = mount(
+            device,
+            dir,
+            "btrfs",
+            options.flags,
+            options.data().as_c_str(),
+        ) {
+            if created {
+                let _ = fs::remove_dir(dir);
+            }
+            return Err(err.into());
+        }
+
+        Ok(Self {
+            path: dir.to_owned(),
+            kind: Kind::Dir { created },
+        })
+    }
+
+    /// Use a directory that is already mounted, and leave it mounted
+    pub fn existing(path: &Path) -> Self {
+        Self {
+            path: path.to_owned(),
+            kind: Kind::Existing,
+        }
+    }
+
+    /// Find a writable mount of the top-level subvolume of the filesystem `fsid`
+    pub fn find_existing(fsid: &[u8; 16]) -> io::Result<Option<Self>> {
+        for entry in mountinfo::read()? {
+            if entry.fs_type != "btrfs" || entry.root != Path::new("/") || entry.is_read_only() {
+                continue;
+            }
+
+            match File::open(&entry.mount_point).and_then(btrfs::fsid) {
+                Ok(other) if other == *fsid => return Ok(Some(Self::existing(&entry.mount_point))),
+                Ok(_) => {}
+                Err(err) => log::debug!(
+                    "failed to get filesystem id of '{}': {err}",
+                    entry.mount_point.display()
+                ),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Where the top-level subvolume can be reached
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Unmount and clean up, reporting any errors instead of only logging them
+    pub fn unmount(mut self) -> io::Result<()> {
+        self.cleanup()
+    }
+
+    fn cleanup(&mut self) -> io::Result<()> {
+        let created = match std::mem::replace(&mut self.kind, Kind::Unmounted) {
+            Kind::Detached { .. } | Kind::Existing | Kind::Unmounted => return Ok(()),
+            Kind::Dir { created } => created,
+        };
+
+        log::trace!("umount '{}'", self.path.display());
+        match unmount(&self.path, UnmountFlags::empty()) {
+            Ok(()) => {}
+            Err(Errno::BUSY) => {
+                log::warn!("filesystem is busy, detaching it to be unmounted once it isn't");
+                unmount(&self.path, UnmountFlags::DETACH)?;
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        if created {
+            log::trace!("rmdir '{}'", self.path.display());
+            fs::remove_dir(&self.path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        if let Err(err) = self.cleanup() {
+            log::error!("failed to clean up mount: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<String, &'static str> {
+        s.parse::<Options>().map(|options| options.to_string())
+    }
+
+    #[test]
+    fn flags_and_btrfs_options() {
+        assert_eq!(
+            parse("noatime, nodev,compress=zstd,compress=zstd").unwrap(),
+            "nodev,noatime,subvolid=5,compress=zstd"
+        );
+        assert_eq!(parse("noatime,atime").unwrap(), "subvolid=5");
+    }
+
+    #[test]
+    fn top_level_only() {
+        assert_eq!(parse("subvolid=05,subvol=/").unwrap(), "subvolid=5");
+        assert!(parse("subvolid=256").is_err());
+        assert!(parse("subvol=/root").is_err());
+    }
+
+    #[test]
+    fn fstab_only_options_are_dropped() {
+        assert_eq!(
+            parse("defaults,nofail,noauto,user,x-systemd.device-timeout=5s,comment=x").unwrap(),
+            "subvolid=5"
+        );
+    }
+
+    #[test]
+    fn read_only_is_rejected() {
+        assert!(parse("rw").is_ok());
+        assert!(parse("noatime,ro").is_err());
+    }
+
+    #[test]
+    fn nul_is_rejected() {
+        assert!(parse("compress\0").is_err());
+    }
+}
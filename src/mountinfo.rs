@@ -0,0 +1,46 @@
+//! Parsing `/proc/self/mountinfo`, to find filesystems that are already mounted.
+
+use std::path::PathBuf;
+use std::{fs, io};
+
+use crate::subvolume::unescape;
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Path inside the filesystem that is mounted
+    pub root: PathBuf,
+    pub mount_point: PathBuf,
+    /// Per-mount options, like `ro` or `nodev`
+    pub options: String,
+    pub fs_type: String,
+}
+
+impl Entry {
+    pub fn is_read_only(&self) -> bool {
+        self.options.split(',').any(|option| option == "ro")
+    }
+}
+
+/// Every mount in this process's mount namespace
+pub fn read() -> io::Result<Vec<Entry>> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    Ok(mountinfo.lines().filter_map(parse).collect())
+}
+
+/// Parse a line like
+/// `36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue`
+fn parse(line: &str) -> Option<Entry> {
+    let (mount, filesystem) = line.split_once(" - ")?;
+    let mut mount = mount.split(' ');
+    let root = mount.nth(3)?;
+    let mount_point = mount.next()?;
+    let options = mount.next()?;
+    let fs_type = filesystem.split(' ').next()?;
+
+    Some(Entry {
+        root: unescape(root).into(),
+        mount_point: unescape(mount_point).into(),
+        options: options.to_owned(),
+        fs_type: unescape(fs_type),
+    })
+}
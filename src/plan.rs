@@ -0,0 +1,550 @@
+//! Deciding everything a run will do before doing any of it, so a dry run can show exactly what
+//! a real one would do.
+
+use std::cmp::Reverse;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde_json::json;
+
+use crate::backups::{self, Backup};
+use crate::{Error, bail, config, retention, space, subvolume, unwrap};
+
+#[derive(Debug)]
+pub struct Plan {
+    /// Where the top-level subvolume is, which paths are shown relative to
+    pub root: PathBuf,
+    pub volumes: Vec<Volume>,
+    /// Only planned when there is a minimum amount of free space
+    pub free_space: Option<FreeSpace>,
+}
+
+#[derive(Debug)]
+pub struct Volume {
+    pub name: String,
+    pub back_up: Option<BackUp>,
+    /// Every backup, including the one made by `back_up`, newest first
+    pub backups: Vec<Decision>,
+    pub create: Option<Create>,
+}
+
+/// Turning the old root volume into a backup
+#[derive(Debug)]
+pub struct BackUp {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    pub mode: backups::Mode,
+    /// Either a subvolume or a plain directory
+    pub kind: subvolume::Kind,
+    pub created: DateTime<Utc>,
+    /// Subvolumes deleted after snapshotting, innermost first
+    pub deleted: Vec<PathBuf>,
+    /// Why deleting the old root volume after snapshotting it is going to fail
+    pub blocked: Option<String>,
+}
+
+/// What happens to an existing backup
+#[derive(Debug)]
+pub struct Decision {
+    pub backup: Backup,
+    /// `None` if the backup is removed
+    pub keep: Option<Keep>,
+    /// Why removing the backup is going to fail
+    pub blocked: Option<String>,
+}
+
+/// Why a backup is kept
+#[derive(Debug, Clone, Copy)]
+pub enum Keep {
+    Pinned(backups::Pin),
+    Policy(retention::Reason),
+}
+
+/// A fresh root volume, either empty or a writable snapshot of `template`
+#[derive(Debug)]
+pub struct Create {
+    pub path: PathBuf,
+    pub template: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub struct FreeSpace {
+    pub space: space::Space,
+    pub min_free: space::MinFree,
+    pub min_keep: u16,
+    /// Backups removed oldest first until `min_free` is met. How much space each one frees can't
+    /// be known in advance, so not all of them may be needed.
+    pub candidates: Vec<PathBuf>,
+}
+
+impl fmt::Display for Keep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pinned(pin) => write!(f, "pinned {pin}"),
+            Self::Policy(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+impl BackUp {
+    /// The backup as it will be listed once it exists
+    fn backup(&self) -> Backup {
+        Backup {
+            path: self.to.clone(),
+            kind: self.kind,
+            created: self.created,
+            source: backups::Source::Name,
+            pin: None,
+        }
+    }
+}
+
+/// Plan how to move the old root of `volume` into its backups, if there is one
+pub fn back_up(
+    mount_dir: &Path,
+    volume: &config::Volume,
+    kind: Option<subvolume::Kind>,
+) -> Result<Option<BackUp>, Error> {
+    let name = &volume.name;
+    let root_volume = mount_dir.join(&volume.subvolume);
+    log::debug!("wiping volume {name}: '{}'", root_volume.display());
+
+    let created = match backups::root_created(&root_volume) {
+        Ok(Some(created)) => created,
+        Ok(None) => {
+            log::debug!("no old volume {name} found");
+            return Ok(None);
+        }
+        Err(err) => bail!("failed to get volume {name}: {err}"),
+    };
+    let backup = mount_dir
+        .join(&volume.backup_dir)
+        .join(created.format(&volume.backup_format).to_string());
+    if backup.symlink_metadata().is_ok() {
+        bail!(
+            "backup of volume {name} already exists: '{}'",
+            backup.display()
+        );
+    }
+
+    // Plain directories can't be snapshotted
+    let (mut mode, kind) = match kind {
+        Some(subvolume::Kind::PlainDir) => (backups::Mode::Rename, subvolume::Kind::PlainDir),
+        _ => (volume.backup_mode, subvolume::Kind::Subvolume),
+    };
+
+    let mut deleted = Vec::new();
+    let mut blocked = None;
+    if let backups::Mode::Snapshot = mode {
+        let tree = unwrap!(
+            subvolume::Tree::scan(&root_volume),
+            "failed to find subvolumes in root volume: {err}"
+        );
+
+        // Snapshots don't include nested subvolumes
+        let nested: Vec<_> = tree
+            .bottom_up()
+            .into_iter()
+            .filter(|s| s.id != tree.id)
+            .collect();
+        if !nested.is_empty() && volume.snapshot_nested == backups::Nested::Rename {
+            log::warn!(
+                "volume {name} contains {} nested subvolumes, moving it into backups instead of snapshotting it so they are kept",
+                nested.len()
+            );
+            mode = backups::Mode::Rename;
+        } else {
+            for nested in nested {
+                log::warn!(
+                    "nested subvolume is not part of the backup and will be deleted: '{}'",
+                    nested.path.display()
+                );
+            }
+            deleted = tree.bottom_up().iter().map(|s| s.path.clone()).collect();
+            blocked = tree.check().err().map(|err| err.to_string());
+        }
+    }
+
+    Ok(Some(BackUp {
+        from: root_volume,
+        to: backup,
+        mode,
+        kind,
+        created,
+        deleted,
+        blocked,
+    }))
+}
+
+/// Decide which of `volume`'s backups to keep, including the one `back_up` is going to make
+pub fn prune(
+    mount_dir: &Path,
+    volume: &config::Volume,
+    fsid: &[u8; 16],
+    now: DateTime<Utc>,
+    back_up: Option<&BackUp>,
+) -> Result<Vec<Decision>, Error> {
+    let mut backups = crate::list_backups(mount_dir, volume, fsid)?;
+    if let Some(back_up) = back_up {
+        backups.push(back_up.backup());
+        backups.sort_by_key(|backup| Reverse(backup.created));
+    }
+
+    Ok(decide(volume, backups, now)
+        .into_iter()
+        .map(|(backup, keep)| {
+            let blocked = keep.is_none().then(|| blocked(&backup, back_up)).flatten();
+            Decision {
+                backup,
+                keep,
+                blocked,
+            }
+        })
+        .collect())
+}
+
+/// Decide which of `volume`'s backups to keep, with `None` for the ones to remove
+///
+/// `backups` must be sorted newest first. Pinned backups are kept without counting towards the
+/// retention policy.
+pub fn decide(
+    volume: &config::Volume,
+    backups: Vec<Backup>,
+    now: DateTime<Utc>,
+) -> Vec<(Backup, Option<Keep>)> {
+    let created: Vec<_> = backups
+        .iter()
+        .filter(|backup| !backup.is_pinned(now))
+        .map(|backup| backup.created)
+        .collect();
+    let mut policy = volume.retention.apply(now, &created).into_iter();
+
+    backups
+        .into_iter()
+        .map(|backup| {
+            let keep = match backup.pin {
+                Some(pin) if backup.is_pinned(now) => Some(Keep::Pinned(pin)),
+                _ => policy.next().flatten().map(Keep::Policy),
+            };
+            (backup, keep)
+        })
+        .collect()
+}
+
+/// Check whether removing `backup` would fail, without removing anything
+fn blocked(backup: &Backup, back_up: Option<&BackUp>) -> Option<String> {
+    if backup.kind == subvolume::Kind::PlainDir {
+        return None;
+    }
+
+    // The new backup doesn't exist yet, but when it is made by renaming it is the same tree as
+    // the old root volume. A snapshot never contains other subvolumes.
+    let path = match back_up {
+        Some(back_up) if back_up.to == backup.path => match back_up.mode {
+            backups::Mode::Rename => &back_up.from,
+            backups::Mode::Snapshot => return None,
+        },
+        _ => &backup.path,
+    };
+
+    match subvolume::Tree::scan(path) {
+        Ok(tree) => tree.check().err().map(|err| err.to_string()),
+        Err(err) => Some(format!("failed to find subvolumes: {err}")),
+    }
+}
+
+/// Plan which backups to remove if there isn't enough free space once `volumes` are pruned
+pub fn free_space(
+    mount_dir: &Path,
+    volumes: &[Volume],
+    config: &config::Config,
+) -> Option<FreeSpace> {
+    if config.min_free.is_disabled() {
+        return None;
+    }
+
+    // Not being able to free up space shouldn't stop the root volumes from being wiped
+    let space = match space::space(mount_dir) {
+        Ok(space) => space,
+        Err(err) => {
+            log::error!("failed to get free space of filesystem: {err}");
+            return None;
+        }
+    };
+
+    let mut candidates = Vec::new();
+    if !config.min_free.is_met(space) {
+        let mut kept: Vec<Vec<_>> = volumes
+            .iter()
+            .map(|volume| {
+                volume
+                    .backups
+                    .iter()
+                    .filter(|decision| matches!(decision.keep, Some(Keep::Policy(_))))
+                    .map(|decision| &decision.backup)
+                    .collect()
+            })
+            .collect();
+        while let Some(oldest) = crate::take_oldest(&mut kept, config.min_free_keep) {
+            candidates.push(oldest.path.clone());
+        }
+    }
+
+    Some(FreeSpace {
+        space,
+        min_free: config.min_free,
+        min_keep: config.min_free_keep,
+        candidates,
+    })
+}
+
+impl Plan {
+    fn relative<'a>(&self, path: &'a Path) -> &'a Path {
+        path.strip_prefix(&self.root).unwrap_or(path)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let path = |path: &Path| self.relative(path).to_string_lossy().into_owned();
+
+        let volumes: Vec<_> = self
+            .volumes
+            .iter()
+            .map(|volume| {
+                let back_up = volume.back_up.as_ref().map(|back_up| {
+                    json!({
+                        "mode": match back_up.mode {
+                            backups::Mode::Rename => "rename",
+                            backups::Mode::Snapshot => "snapshot",
+                        },
+                        "from": path(&back_up.from),
+                        "to": path(&back_up.to),
+                        "deleted": back_up.deleted.iter().map(|p| path(p)).collect::<Vec<_>>(),
+                        "blocked": back_up.blocked,
+                    })
+                });
+                let backups: Vec<_> = volume
+                    .backups
+                    .iter()
+                    .map(|decision| {
+                        json!({
+                            "path": path(&decision.backup.path),
+                            "created": decision.backup.created.to_rfc3339(),
+                            "dated-by": decision.backup.source.to_string(),
+                            "action": if decision.keep.is_some() { "keep" } else { "remove" },
+                            "reason": decision.keep.map(|keep| keep.to_string()),
+                            "blocked": decision.blocked,
+                        })
+                    })
+                    .collect();
+                let create = volume.create.as_ref().map(|create| {
+                    json!({
+                        "path": path(&create.path),
+                        "template": create.template.as_deref().map(path),
+                    })
+                });
+
+                json!({
+                    "name": volume.name,
+                    "back-up": back_up,
+                    "backups": backups,
+                    "create": create,
+                })
+            })
+            .collect();
+
+        let free_space = self.free_space.as_ref().map(|free| {
+            json!({
+                "available": free.space.available,
+                "total": free.space.total,
+                "minimum": free.min_free.to_string(),
+                "min-keep": free.min_keep,
+                "candidates": free.candidates.iter().map(|p| path(p)).collect::<Vec<_>>(),
+            })
+        });
+
+        json!({
+            "volumes": volumes,
+            "free-space": free_space,
+        })
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for volume in &self.volumes {
+            writeln!(f, "{}:", volume.name)?;
+
+            if let Some(back_up) = &volume.back_up {
+                let (from, to) = (self.relative(&back_up.from), self.relative(&back_up.to));
+                match back_up.mode {
+                    backups::Mode::Rename => {
+                        writeln!(f, "  move '{}' to '{}'", from.display(), to.display())?;
+                    }
+                    backups::Mode::Snapshot => {
+                        write!(
+                            f,
+                            "  snapshot '{}' read-only as '{}'",
+                            from.display(),
+                            to.display()
+                        )?;
+                        match &back_up.blocked {
+                            Some(blocked) => writeln!(f, ", which will fail: {blocked}")?,
+                            None => writeln!(f)?,
+                        }
+                        for deleted in &back_up.deleted {
+                            writeln!(
+                                f,
+                                "  delete subvolume '{}'",
+                                self.relative(deleted).display()
+                            )?;
+                        }
+                    }
+                }
+            }
+
+            if let Some(create) = &volume.create {
+                let path = self.relative(&create.path).display();
+                match &create.template {
+                    Some(template) => writeln!(
+                        f,
+                        "  snapshot '{}' as '{path}'",
+                        self.relative(template).display()
+                    )?,
+                    None => writeln!(f, "  create empty subvolume '{path}'")?,
+                }
+            }
+
+            for decision in &volume.backups {
+                let path = self.relative(&decision.backup.path).display();
+                match (decision.keep, &decision.blocked) {
+                    (Some(keep), _) => writeln!(f, "  keep '{path}' ({keep})")?,
+                    (None, None) => writeln!(f, "  remove '{path}'")?,
+                    (None, Some(blocked)) => {
+                        writeln!(f, "  remove '{path}', which will fail: {blocked}")?;
+                    }
+                }
+            }
+        }
+
+        if let Some(free) = &self.free_space {
+            writeln!(
+                f,
+                "free space: {} of {} bytes available, minimum is {}",
+                free.space.available, free.space.total, free.min_free
+            )?;
+            if !free.candidates.is_empty() {
+                writeln!(
+                    f,
+                    "  until the minimum is met, keeping at least {} backups of each volume:",
+                    free.min_keep
+                )?;
+                for candidate in &free.candidates {
+                    writeln!(f, "  remove '{}'", self.relative(candidate).display())?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::TimeZone as _;
+
+    use super::*;
+    use crate::backups::Pin;
+    use crate::retention::Reason;
+
+    fn volume(last: u16) -> config::Volume {
+        config::Volume {
+            name: "root".to_owned(),
+            subvolume: PathBuf::from("root"),
+            backup_dir: PathBuf::from("root-backups"),
+            backup_format: "%Y%m%d_%H%M%S".to_owned(),
+            backup_mode: backups::Mode::Rename,
+            snapshot_nested: backups::Nested::Rename,
+            template: None,
+            plain_dirs: subvolume::PlainDirs::Skip,
+            retention: retention::Policy {
+                last,
+                within: Duration::ZERO,
+                hourly: 0,
+                daily: 0,
+                weekly: 0,
+                monthly: 0,
+            },
+        }
+    }
+
+    fn day(day: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 10, day, 12, 0, 0).unwrap()
+    }
+
+    fn backup(created: u32, pin: Option<Pin>) -> Backup {
+        Backup {
+            path: PathBuf::from(format!("root-backups/{created}")),
+            kind: subvolume::Kind::Subvolume,
+            created: day(created),
+            source: backups::Source::Name,
+            pin,
+        }
+    }
+
+    fn decide(last: u16, now: u32, backups: Vec<Backup>) -> Vec<Option<Keep>> {
+        super::decide(&volume(last), backups, day(now))
+            .into_iter()
+            .map(|(_, keep)| keep)
+            .collect()
+    }
+
+    #[test]
+    fn pinned_backups_dont_count_towards_policy() {
+        let keep = decide(
+            1,
+            18,
+            vec![
+                backup(17, Some(Pin::Forever)),
+                backup(16, None),
+                backup(15, None),
+            ],
+        );
+        assert!(matches!(
+            keep[..],
+            [
+                Some(Keep::Pinned(Pin::Forever)),
+                Some(Keep::Policy(Reason::Last)),
+                None
+            ]
+        ));
+    }
+
+    #[test]
+    fn expired_pins_are_ignored() {
+        let keep = decide(
+            0,
+            18,
+            vec![
+                backup(17, Some(Pin::Until(day(19)))),
+                backup(16, Some(Pin::Until(day(18)))),
+            ],
+        );
+        assert!(matches!(
+            keep[..],
+            [Some(Keep::Pinned(Pin::Until(_))), None]
+        ));
+    }
+
+    #[test]
+    fn expired_pins_count_towards_policy() {
+        let keep = decide(
+            1,
+            18,
+            vec![backup(17, Some(Pin::Until(day(10)))), backup(16, None)],
+        );
+        assert!(matches!(keep[..], [Some(Keep::Policy(Reason::Last)), None]));
+    }
+}
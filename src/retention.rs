@@ -0,0 +1,267 @@
+use std::fmt;
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike as _, Utc};
+
+/// Bucketed (grandfather-father-son) retention policy.
+///
+/// A backup is kept if any rule matches it:
+///
+/// - it is one of the newest `last` backups
+/// - it is younger than `within`
+/// - it is the oldest backup of one of the last `hourly` hours, `daily` days, `weekly` weeks, or
+///   `monthly` months (calendar periods in UTC, including the current one)
+///
+/// Keeping the oldest backup of a period means its representative doesn't change as more backups
+/// are made during that period.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    pub last: u16,
+    pub within: Duration,
+    pub hourly: u16,
+    pub daily: u16,
+    pub weekly: u16,
+    pub monthly: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    Last,
+    Within,
+    Hourly,
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Last => "last",
+            Self::Within => "within",
+            Self::Hourly => "hourly",
+            Self::Daily => "daily",
+            Self::Weekly => "weekly",
+            Self::Monthly => "monthly",
+        })
+    }
+}
+
+impl Policy {
+    /// Decide which backups to keep.
+    ///
+    /// `backups` must be sorted newest first. The result is in the same order, with `None` for
+    /// backups that should be removed.
+    pub fn apply(&self, now: DateTime<Utc>, backups: &[DateTime<Utc>]) -> Vec<Option<Reason>> {
+        let mut keep = vec![None; backups.len()];
+
+        for reason in keep.iter_mut().take(self.last.into()) {
+            *reason = Some(Reason::Last);
+        }
+
+        for (reason, created) in keep.iter_mut().zip(backups) {
+            let age = (now - *created).to_std().unwrap_or_default();
+            if reason.is_none() && age < self.within {
+                *reason = Some(Reason::Within);
+            }
+        }
+
+        let periods: [(Reason, u16, Period); 4] = [
+            (Reason::Hourly, self.hourly, |t| {
+                t.timestamp().div_euclid(3600)
+            }),
+            (Reason::Daily, self.daily, day),
+            (Reason::Weekly, self.weekly, |t| {
+                (day(t) - i64::from(t.weekday().num_days_from_monday())).div_euclid(7)
+            }),
+            (Reason::Monthly, self.monthly, |t| {
+                i64::from(t.year()) * 12 + i64::from(t.month0())
+            }),
+        ];
+
+        for (period_reason, count, period) in periods {
+            let current = period(&now);
+            let mut last_period = None;
+
+            // Oldest first, so the first backup seen in each period is the one kept
+            for (reason, created) in keep.iter_mut().zip(backups).rev() {
+                let p = period(created);
+                if current - p >= count.into() || last_period == Some(p) {
+                    continue;
+                }
+
+                last_period = Some(p);
+                if reason.is_none() {
+                    *reason = Some(period_reason);
+                }
+            }
+        }
+
+        keep
+    }
+}
+
+/// Maps a timestamp to a consecutive period index
+type Period = fn(&DateTime<Utc>) -> i64;
+
+fn day(t: &DateTime<Utc>) -> i64 {
+    t.date_naive().num_days_from_ce().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+
+    use super::*;
+
+    const NONE: Policy = Policy {
+        last: 0,
+        within: Duration::ZERO,
+        hourly: 0,
+        daily: 0,
+        weekly: 0,
+        monthly: 0,
+    };
+
+    fn t(s: &str) -> DateTime<Utc> {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+            .unwrap()
+            .and_utc()
+    }
+
+    fn apply(policy: &Policy, now: &str, backups: &[&str]) -> Vec<Option<Reason>> {
+        let backups: Vec<_> = backups.iter().map(|b| t(b)).collect();
+        policy.apply(t(now), &backups)
+    }
+
+    #[test]
+    fn nothing_kept_by_default() {
+        let keep = apply(&NONE, "2026-10-18 12:00:00", &["2026-10-18 11:00:00"]);
+        assert_eq!(keep, [None]);
+    }
+
+    #[test]
+    fn last() {
+        let policy = Policy { last: 2, ..NONE };
+        let keep = apply(
+            &policy,
+            "2026-10-18 12:00:00",
+            &[
+                "2026-10-18 11:00:00",
+                "2026-10-10 11:00:00",
+                "2025-01-01 00:00:00",
+            ],
+        );
+        assert_eq!(keep, [Some(Reason::Last), Some(Reason::Last), None]);
+    }
+
+    #[test]
+    fn within_is_exclusive() {
+        let policy = Policy {
+            within: Duration::from_secs(3600),
+            ..NONE
+        };
+        let keep = apply(
+            &policy,
+            "2026-10-18 12:00:00",
+            &["2026-10-18 11:00:01", "2026-10-18 11:00:00"],
+        );
+        assert_eq!(keep, [Some(Reason::Within), None]);
+    }
+
+    #[test]
+    fn earlier_rules_win() {
+        let policy = Policy {
+            last: 1,
+            daily: 1,
+            ..NONE
+        };
+        let keep = apply(&policy, "2026-10-18 12:00:00", &["2026-10-18 11:00:00"]);
+        assert_eq!(keep, [Some(Reason::Last)]);
+    }
+
+    #[test]
+    fn hourly_keeps_oldest_of_each_hour() {
+        let policy = Policy { hourly: 2, ..NONE };
+        let keep = apply(
+            &policy,
+            "2026-10-18 10:55:00",
+            &[
+                "2026-10-18 10:50:00",
+                "2026-10-18 10:10:00",
+                "2026-10-18 09:40:00",
+                "2026-10-18 09:05:00",
+                "2026-10-18 08:30:00",
+            ],
+        );
+        let hourly = Some(Reason::Hourly);
+        assert_eq!(keep, [None, hourly, None, hourly, None]);
+    }
+
+    #[test]
+    fn daily_includes_current_day() {
+        let policy = Policy { daily: 1, ..NONE };
+        let keep = apply(
+            &policy,
+            "2026-10-18 12:00:00",
+            &["2026-10-18 00:00:00", "2026-10-17 23:59:59"],
+        );
+        assert_eq!(keep, [Some(Reason::Daily), None]);
+    }
+
+    #[test]
+    fn daily_counts_calendar_days() {
+        let policy = Policy { daily: 3, ..NONE };
+        let keep = apply(
+            &policy,
+            "2026-10-18 12:00:00",
+            &["2026-10-16 12:00:00", "2026-10-15 12:00:00"],
+        );
+        assert_eq!(keep, [Some(Reason::Daily), None]);
+    }
+
+    #[test]
+    fn weeks_start_on_monday() {
+        let policy = Policy { weekly: 1, ..NONE };
+        // 2026-10-18 is a Sunday
+        let keep = apply(
+            &policy,
+            "2026-10-18 12:00:00",
+            &[
+                "2026-10-13 12:00:00",
+                "2026-10-12 00:00:00",
+                "2026-10-11 23:59:59",
+            ],
+        );
+        assert_eq!(keep, [None, Some(Reason::Weekly), None]);
+    }
+
+    #[test]
+    fn monthly_keeps_oldest_of_each_month() {
+        let policy = Policy { monthly: 2, ..NONE };
+        let keep = apply(
+            &policy,
+            "2026-10-18 12:00:00",
+            &[
+                "2026-10-05 12:00:00",
+                "2026-10-01 00:00:00",
+                "2026-09-30 23:59:59",
+                "2026-09-15 12:00:00",
+                "2026-08-31 12:00:00",
+            ],
+        );
+        let monthly = Some(Reason::Monthly);
+        assert_eq!(keep, [None, monthly, None, monthly, None]);
+    }
+
+    #[test]
+    fn months_span_years() {
+        let policy = Policy { monthly: 2, ..NONE };
+        let keep = apply(
+            &policy,
+            "2026-01-10 12:00:00",
+            &["2025-12-20 12:00:00", "2025-11-20 12:00:00"],
+        );
+        assert_eq!(keep, [Some(Reason::Monthly), None]);
+    }
+}
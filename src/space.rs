@@ -0,0 +1,80 @@
+use std::path::Path;
+use std::str::FromStr;
+use std::{fmt, io};
+
+/// Minimum amount of free space to keep on the filesystem
+#[derive(Debug, Clone, Copy)]
+pub enum MinFree {
+    Bytes(u64),
+    Percent(u8),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Space {
+    pub available: u64,
+    pub total: u64,
+}
+
+pub fn space(path: &Path) -> io::Result<Space> {
+    let stat = rustix::fs::statfs(path)?;
+    let block_size = u64::try_from(stat.f_bsize).unwrap_or_default();
+    Ok(Space {
+        available: stat.f_bavail.saturating_mul(block_size),
+        total: stat.f_blocks.saturating_mul(block_size),
+    })
+}
+
+impl MinFree {
+    pub fn is_disabled(self) -> bool {
+        matches!(self, Self::Bytes(0) | Self::Percent(0))
+    }
+
+    pub fn is_met(self, space: Space) -> bool {
+        match self {
+            Self::Bytes(bytes) => space.available >= bytes,
+            Self::Percent(percent) => {
+                u128::from(space.available) * 100 >= u128::from(space.total) * u128::from(percent)
+            }
+        }
+    }
+}
+
+impl FromStr for MinFree {
+    type Err = &'static str;
+
+    /// Parses either a percentage (`15%`) or a size with an optional binary unit (`20G`, `20GiB`)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(percent) = s.strip_suffix('%') {
+            return match percent.trim().parse() {
+                Ok(percent @ 0..=100) => Ok(Self::Percent(percent)),
+                _ => Err("expected a percentage between 0% and 100%"),
+            };
+        }
+
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let number: u64 = number.parse().map_err(|_| "expected a number")?;
+        let shift = match unit.trim() {
+            "" | "B" => 0,
+            "K" | "KiB" => 10,
+            "M" | "MiB" => 20,
+            "G" | "GiB" => 30,
+            "T" | "TiB" => 40,
+            _ => return Err("unknown unit, expected one of B, KiB, MiB, GiB, TiB"),
+        };
+        number
+            .checked_mul(1 << shift)
+            .map(Self::Bytes)
+            .ok_or("size is too large")
+    }
+}
+
+impl fmt::Display for MinFree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bytes(bytes) => write!(f, "{bytes} bytes"),
+            Self::Percent(percent) => write!(f, "{percent}%"),
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::os::unix::fs::MetadataExt as _;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{fmt, io};
+
+use crate::btrfs;
+
+/// What is at a path that should be a subvolume
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Subvolume,
+    /// A directory that isn't the root of a subvolume
+    PlainDir,
+    Symlink,
+    /// A regular file, device node, socket, etc
+    File,
+    /// Something on a different filesystem, usually a mount point
+    Foreign,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Subvolume => "a subvolume",
+            Self::PlainDir => "a plain directory",
+            Self::Symlink => "a symlink",
+            Self::File => "not a directory",
+            Self::Foreign => "on a different filesystem",
+        })
+    }
+}
+
+/// Find out what is at `path`, which should be on the btrfs filesystem `fsid`
+pub fn kind(path: &Path, fsid: &[u8; 16]) -> io::Result<Kind> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_symlink() {
+        return Ok(Kind::Symlink);
+    }
+    if !metadata.is_dir() {
+        return Ok(Kind::File);
+    }
+
+    let file = File::open(path)?;
+    if rustix::fs::fstatfs(&file)?.f_type as u32 != btrfs::SUPER_MAGIC
+        || btrfs::fsid(&file)? != *fsid
+    {
+        return Ok(Kind::Foreign);
+    }
+
+    Ok(if metadata.ino() == btrfs::FIRST_FREE_OBJECTID {
+        Kind::Subvolume
+    } else {
+        Kind::PlainDir
+    })
+}
+
+/// What to do with plain directories where subvolumes are expected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlainDirs {
+    /// Leave them alone
+    Skip,
+    /// Treat them like subvolumes, deleting them with their contents
+    Remove,
+}
+
+impl FromStr for PlainDirs {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "remove" => Ok(Self::Remove),
+            _ => Err("expected skip or remove"),
+        }
+    }
+}
+
+/// A subvolume and all subvolumes nested inside it
+#[derive(Debug)]
+pub struct Tree {
+    pub path: PathBuf,
+    pub id: u64,
+    read_only: bool,
+    dev: u64,
+    pub nested: Vec<Tree>,
+}
+
+#[derive(Debug)]
+pub enum DeleteError {
+    /// Nothing was deleted because the tree can't be deleted completely
+    Blocked { path: PathBuf, reason: &'static str },
+    /// Deleting failed partway through
+    Failed {
+        path: PathBuf,
+        err: io::Error,
+        deleted: usize,
+    },
+}
+
+impl fmt::Display for DeleteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Blocked { path, reason } => write!(
+                f,
+                "nothing deleted, subvolume '{}' {reason}",
+                path.display()
+            ),
+            Self::Failed { path, err, deleted } => write!(
+                f,
+                "failed to delete '{}' after deleting {deleted} nested subvolumes: {err}",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl Tree {
+    /// Find the subvolume at `path` and everything nested inside it
+    pub fn scan(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let info = btrfs::subvolume_info(&file)?;
+        let dev = file.metadata()?.dev();
+
+        let nested = btrfs::nested_subvolumes(&file, info.id)?
+            .into_iter()
+            .map(|nested| {
+                let tree = Self::scan(&path.join(&nested.path))?;
+                if tree.id != nested.id {
+                    return Err(io::Error::other(format!(
+                        "expected subvolume {} at '{}', found {}",
+                        nested.id,
+                        tree.path.display(),
+                        tree.id
+                    )));
+                }
+                Ok(tree)
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            id: info.id,
+            read_only: info.read_only,
+            dev,
+            nested,
+        })
+    }
+
+    /// All subvolumes in the order they need to be deleted, innermost first
+    pub fn bottom_up(&self) -> Vec<&Self> {
+        let mut order = Vec::new();
+        self.push_bottom_up(&mut order);
+        order
+    }
+
+    fn push_bottom_up<'a>(&'a self, order: &mut Vec<&'a Self>) {
+        for nested in &self.nested {
+            nested.push_bottom_up(order);
+        }
+        order.push(self);
+    }
+
+    /// Check for anything that would stop the whole tree from being deleted
+    ///
+    /// A subvolume that is being sent can't be deleted either, but that can't be checked ahead of
+    /// time.
+    pub fn check(&self) -> Result<(), DeleteError> {
+        let swap_devs = swap_devices();
+        let default = File::open(&self.path).and_then(btrfs::default_subvolume);
+        for subvolume in self.bottom_up() {
+            let blocked = |reason| {
+                Err(DeleteError::Blocked {
+                    path: subvolume.path.clone(),
+                    reason,
+                })
+            };
+
+            // Entries can't be unlinked from a read-only subvolume
+            if subvolume.read_only && !subvolume.nested.is_empty() {
+                return blocked("is read-only and contains other subvolumes");
+            }
+            if swap_devs.contains(&subvolume.dev) {
+                return blocked("contains an active swapfile");
+            }
+            match &default {
+                Ok(default) if *default == subvolume.id => {
+                    return blocked("is the default subvolume");
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    log::debug!("failed to get default subvolume: {err}");
+                    return blocked("might be the default subvolume");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Delete the tree bottom-up, after checking that all of it can be deleted
+    ///
+    /// Returns the number of subvolumes deleted.
+    pub fn delete(&self) -> Result<usize, DeleteError> {
+        self.check()?;
+
+        let mut deleted = 0;
+        for subvolume in self.bottom_up() {
+            if let Err(err) = btrfs::destroy_subvolume(&subvolume.path) {
+                return Err(DeleteError::Failed {
+                    path: subvolume.path.clone(),
+                    err,
+                    deleted,
+                });
+            }
+
+            log::info!(
+                "deleted subvolume {}: '{}'",
+                subvolume.id,
+                subvolume.path.display()
+            );
+            deleted += 1;
+        }
+
+        Ok(deleted)
+    }
+}
+
+/// Device numbers of the subvolumes containing active swapfiles
+///
+/// Each btrfs subvolume has its own device number, which is the same no matter where it is
+/// mounted.
+fn swap_devices() -> HashSet<u64> {
+    let swaps = match fs::read_to_string("/proc/swaps") {
+        Ok(swaps) => swaps,
+        Err(err) => {
+            log::debug!("failed to read /proc/swaps: {err}");
+            return HashSet::new();
+        }
+    };
+
+    swaps
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let path = unescape(fields.next()?);
+            (fields.next()? == "file").then_some(path)
+        })
+        .filter_map(|path| fs::metadata(path).ok())
+        .map(|metadata| metadata.dev())
+        .collect()
+}
+
+/// Undo the octal escapes used for whitespace in `/proc` files
+pub fn unescape(s: &str) -> String {
+    let mut unescaped = Vec::with_capacity(s.len());
+    let mut bytes = s.as_bytes();
+    while let Some((&b, rest)) = bytes.split_first() {
+        if b == b'\\'
+            && let Some(octal) = rest.get(..3)
+            && let Ok(octal) = std::str::from_utf8(octal)
+            && let Ok(c) = u8::from_str_radix(octal, 8)
+        {
+            unescaped.push(c);
+            bytes = &rest[3..];
+        } else {
+            unescaped.push(b);
+            bytes = rest;
+        }
+    }
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
@@ -0,0 +1,239 @@
+//! Reading the btrfs superblock straight from a block device, for when the filesystem isn't
+//! mounted yet.
+
+use std::fs::File;
+use std::os::unix::fs::FileExt as _;
+use std::path::Path;
+use std::{fmt, io};
+
+/// Offset of the primary superblock
+const OFFSET: u64 = 0x1_0000;
+const SIZE: usize = 0x1000;
+
+/// The checksum covers everything after itself
+const CSUM_SIZE: usize = 0x20;
+const CSUM_TYPE_OFFSET: usize = 0xc4;
+const MAGIC: &[u8; 8] = b"_BHRfS_M";
+const MAGIC_OFFSET: usize = 0x40;
+const FSID_OFFSET: usize = 0x20;
+const NUM_DEVICES_OFFSET: usize = 0x88;
+/// `devid` is the first field of the embedded `btrfs_dev_item`
+const DEVID_OFFSET: usize = 0xc9;
+const LABEL_OFFSET: usize = 0x12b;
+const LABEL_SIZE: usize = 0x100;
+
+#[derive(Debug, Clone)]
+pub struct Superblock {
+    pub fsid: [u8; 16],
+    pub label: String,
+    /// Number of devices in the filesystem
+    pub num_devices: u64,
+    /// Which of those devices this is
+    pub devid: u64,
+    pub checksum: Checksum,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checksum {
+    Valid,
+    Invalid,
+    /// A checksum algorithm that isn't implemented here: sha256, blake2b, or an unknown one
+    Unsupported(u16),
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Valid => f.write_str("valid"),
+            Self::Invalid => f.write_str("invalid"),
+            Self::Unsupported(1) => f.write_str("xxhash64"),
+            Self::Unsupported(2) => f.write_str("sha256"),
+            Self::Unsupported(3) => f.write_str("blake2b"),
+            Self::Unsupported(kind) => write!(f, "unknown type {kind}"),
+        }
+    }
+}
+
+/// Read the superblock of the device at `path`, or `None` if it doesn't contain btrfs
+pub fn read(path: &Path) -> io::Result<Option<Superblock>> {
+    let mut raw = vec![0; SIZE];
+    match File::open(path)?.read_exact_at(&mut raw, OFFSET) {
+        Ok(()) => {}
+        // Too small to contain btrfs
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+
+    if &raw[MAGIC_OFFSET..MAGIC_OFFSET + MAGIC.len()] != MAGIC {
+        return Ok(None);
+    }
+
+    let mut fsid = [0; 16];
+    fsid.copy_from_slice(&raw[FSID_OFFSET..FSID_OFFSET + 16]);
+    let label = &raw[LABEL_OFFSET..LABEL_OFFSET + LABEL_SIZE];
+    let label = label.split(|&b| b == 0).next().unwrap_or_default();
+
+    let csum_type = u16::from_le_bytes([raw[CSUM_TYPE_OFFSET], raw[CSUM_TYPE_OFFSET + 1]]);
+    let valid = match csum_type {
+        0 => Some(crc32c(&raw[CSUM_SIZE..]) == u32::from_le_bytes(raw[..4].try_into().unwrap())),
+        1 => Some(xxhash64(&raw[CSUM_SIZE..]) == le_u64(&raw, 0)),
+        _ => None,
+    };
+    let checksum = match valid {
+        Some(true) => Checksum::Valid,
+        Some(false) => Checksum::Invalid,
+        None => Checksum::Unsupported(csum_type),
+    };
+
+    Ok(Some(Superblock {
+        fsid,
+        label: String::from_utf8_lossy(label).into_owned(),
+        num_devices: le_u64(&raw, NUM_DEVICES_OFFSET),
+        devid: le_u64(&raw, DEVID_OFFSET),
+        checksum,
+    }))
+}
+
+fn le_u64(raw: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(raw[offset..offset + 8].try_into().unwrap())
+}
+
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+    for &b in data {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            crc = if crc & 1 == 0 {
+                crc >> 1
+            } else {
+                (crc >> 1) ^ 0x82f6_3b78
+            };
+        }
+    }
+    !crc
+}
+
+/// XXH64 with a seed of 0
+fn xxhash64(data: &[u8]) -> u64 {
+    const P1: u64 = 0x9e37_79b1_85eb_ca87;
+    const P2: u64 = 0xc2b2_ae3d_27d4_eb4f;
+    const P3: u64 = 0x1656_67b1_9e37_79f9;
+    const P4: u64 = 0x85eb_ca77_c2b2_ae63;
+    const P5: u64 = 0x27d4_eb2f_1656_67c5;
+
+    fn round(acc: u64, input: u64) -> u64 {
+        acc.wrapping_add(input.wrapping_mul(P2))
+            .rotate_left(31)
+            .wrapping_mul(P1)
+    }
+    fn merge(hash: u64, acc: u64) -> u64 {
+        (hash ^ round(0, acc)).wrapping_mul(P1).wrapping_add(P4)
+    }
+    let u64_at = |chunk: &[u8]| u64::from_le_bytes(chunk[..8].try_into().unwrap());
+
+    let mut rest = data;
+    let mut hash = if data.len() >= 32 {
+        let mut acc = [P1.wrapping_add(P2), P2, 0, P1.wrapping_neg()];
+        while rest.len() >= 32 {
+            for (i, acc) in acc.iter_mut().enumerate() {
+                *acc = round(*acc, u64_at(&rest[i * 8..]));
+            }
+            rest = &rest[32..];
+        }
+        let hash = acc[0]
+            .rotate_left(1)
+            .wrapping_add(acc[1].rotate_left(7))
+            .wrapping_add(acc[2].rotate_left(12))
+            .wrapping_add(acc[3].rotate_left(18));
+        acc.into_iter().fold(hash, merge)
+    } else {
+        P5
+    };
+    hash = hash.wrapping_add(data.len() as u64);
+
+    while rest.len() >= 8 {
+        hash ^= round(0, u64_at(rest));
+        hash = hash.rotate_left(27).wrapping_mul(P1).wrapping_add(P4);
+        rest = &rest[8..];
+    }
+    if rest.len() >= 4 {
+        let word = u32::from_le_bytes(rest[..4].try_into().unwrap());
+        hash ^= u64::from(word).wrapping_mul(P1);
+        hash = hash.rotate_left(23).wrapping_mul(P2).wrapping_add(P3);
+        rest = &rest[4..];
+    }
+    for &b in rest {
+        hash ^= u64::from(b).wrapping_mul(P5);
+        hash = hash.rotate_left(11).wrapping_mul(P1);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(P2);
+    hash ^= hash >> 29;
+    hash = hash.wrapping_mul(P3);
+    hash ^ (hash >> 32)
+}
+
+/// Format a filesystem UUID the way `blkid` and `/dev/disk/by-uuid` do
+pub fn format_uuid(uuid: &[u8; 16]) -> String {
+    let mut s = String::with_capacity(36);
+    for (i, b) in uuid.iter().enumerate() {
+        if matches!(i, 4 | 6 | 8 | 10) {
+            s.push('-');
+        }
+        s.push_str(&format!("{b:02x}"));
+    }
+    s
+}
+
+/// Parse a filesystem UUID as formatted by [`format_uuid`], ignoring case
+pub fn parse_uuid(s: &str) -> Result<[u8; 16], &'static str> {
+    const ERR: &str = "expected a UUID like 01234567-89ab-cdef-0123-456789abcdef";
+
+    let hex: Vec<u8> = s.bytes().filter(|&b| b != b'-').collect();
+    if hex.len() != 32 {
+        return Err(ERR);
+    }
+
+    let mut uuid = [0; 16];
+    for (byte, pair) in uuid.iter_mut().zip(hex.chunks(2)) {
+        let pair = std::str::from_utf8(pair).map_err(|_| ERR)?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| ERR)?;
+    }
+
+    // Dashes in the wrong places
+    if format_uuid(&uuid) != s.to_ascii_lowercase() {
+        return Err(ERR);
+    }
+    Ok(uuid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn xxhash64_known_values() {
+        assert_eq!(xxhash64(b""), 0xef46_db37_51d8_e999);
+        assert_eq!(xxhash64(b"abc"), 0x44bc_2cf5_ad77_0999);
+        assert_eq!(
+            xxhash64(b"Nobody inspects the spammish repetition"),
+            0xfbce_a83c_8a37_8bf1
+        );
+    }
+
+    #[test]
+    fn uuid_round_trip() {
+        let uuid = "01234567-89ab-cdef-0123-456789abcdef";
+        assert_eq!(
+            format_uuid(&parse_uuid(&uuid.to_uppercase()).unwrap()),
+            uuid
+        );
+        assert!(parse_uuid("0123").is_err());
+    }
+}